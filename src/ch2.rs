@@ -1,5 +1,10 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::io;
+use std::io::BufRead;
+use std::io::IsTerminal;
+
+use rayon::prelude::*;
 
 use crate::common::*;
 
@@ -11,25 +16,76 @@ pub fn ch2() {
     for common_part in common_parts_of_closest_strings("ch2.txt") {
         println!("{}", common_part);
     }
+    for diff in diffs_of_closest_strings("ch2.txt", DiffStyle::for_stdout()) {
+        println!("{}", diff);
+    }
 }
 
 pub fn checksum_for_ids_in_file(file_name: &str) -> usize {
-    let ids = read_lines_from_file(file_name);
+    checksum_for_ids(open_file_buffered(file_name))
+}
+
+pub fn checksum_for_ids<R: BufRead>(ids: R) -> usize {
+    let mut counts = KindCounts::default();
+    for id in ids.lines() {
+        counts.add(&id.expect("Error during reading an id"));
+    }
+    counts.checksum()
+}
+
+/// Same as `checksum_for_ids`, but reads at most `chunk_size` ids at a time
+/// and counts letters of each chunk in parallel.
+pub fn checksum_for_ids_parallel<R: BufRead>(ids: R, chunk_size: usize) -> usize {
+    assert!(chunk_size > 0);
+
+    let mut counts = KindCounts::default();
+    let mut chunk = Vec::with_capacity(chunk_size);
+    for id in ids.lines() {
+        chunk.push(id.expect("Error during reading an id"));
+        if chunk.len() == chunk_size {
+            counts = counts.merge(KindCounts::of_chunk(&chunk));
+            chunk.clear();
+        }
+    }
+    counts.merge(KindCounts::of_chunk(&chunk)).checksum()
+}
 
-    let mut first_kind_appeared = 0;
-    let mut second_kind_appeared = 0;
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+struct KindCounts {
+    first_kind_appeared: usize,
+    second_kind_appeared: usize,
+}
+
+impl KindCounts {
+    fn of_chunk(ids: &[String]) -> KindCounts {
+        ids.par_iter()
+            .fold(KindCounts::default, |mut counts, id| {
+                counts.add(id);
+                counts
+            })
+            .reduce(KindCounts::default, KindCounts::merge)
+    }
 
-    for id in ids {
-        let counts = unique_letter_counts(&id);
+    fn add(&mut self, id: &str) {
+        let counts = unique_letter_counts(id);
         if counts.contains(&FIRST_KIND_COUNT) {
-            first_kind_appeared += 1;
+            self.first_kind_appeared += 1;
         }
         if counts.contains(&SECOND_KIND_COUNT) {
-            second_kind_appeared += 1;
+            self.second_kind_appeared += 1;
+        }
+    }
+
+    fn merge(self, other: KindCounts) -> KindCounts {
+        KindCounts {
+            first_kind_appeared: self.first_kind_appeared + other.first_kind_appeared,
+            second_kind_appeared: self.second_kind_appeared + other.second_kind_appeared,
         }
     }
 
-    first_kind_appeared * second_kind_appeared
+    fn checksum(&self) -> usize {
+        self.first_kind_appeared * self.second_kind_appeared
+    }
 }
 
 fn unique_letter_counts(str: &str) -> HashSet<usize> {
@@ -46,6 +102,32 @@ pub fn common_parts_of_closest_strings(file_name: &str) -> Vec<String> {
     find_strings_with_distance_less_than(&ids, 1).iter().map(|string_pair| string_pair.common_part()).collect()
 }
 
+pub fn diffs_of_closest_strings(file_name: &str, style: DiffStyle) -> Vec<String> {
+    let ids = read_lines_from_file(file_name);
+    find_strings_with_distance_less_than(&ids, 1).iter().map(|string_pair| string_pair.diff(style)).collect()
+}
+
+const ANSI_RED: &str = "\x1b[31m";
+const ANSI_RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffStyle {
+    /// Both strings one under another, followed by a line with `^` under each differing position
+    Carets,
+    /// Both strings one under another, with differing characters highlighted in red
+    Colored,
+}
+
+impl DiffStyle {
+    pub fn for_stdout() -> DiffStyle {
+        if io::stdout().is_terminal() {
+            DiffStyle::Colored
+        } else {
+            DiffStyle::Carets
+        }
+    }
+}
+
 struct StringPair<'a> {
     pub s1: &'a str,
     pub s2: &'a str,
@@ -61,6 +143,31 @@ impl <'a> StringPair<'a> {
         }
         result
     }
+
+    fn diff(&self, style: DiffStyle) -> String {
+        let differs: Vec<bool> = self.s1.chars().zip(self.s2.chars()).map(|(c1, c2)| c1 != c2).collect();
+        match style {
+            DiffStyle::Carets => {
+                let carets: String = differs.iter().map(|&d| if d { '^' } else { ' ' }).collect();
+                format!("{}\n{}\n{}", self.s1, self.s2, carets.trim_end())
+            }
+            DiffStyle::Colored => format!("{}\n{}", highlight(self.s1, &differs), highlight(self.s2, &differs)),
+        }
+    }
+}
+
+fn highlight(str: &str, differs: &[bool]) -> String {
+    let mut result = String::new();
+    for (c, &d) in str.chars().zip(differs.iter()) {
+        if d {
+            result.push_str(ANSI_RED);
+            result.push(c);
+            result.push_str(ANSI_RESET);
+        } else {
+            result.push(c);
+        }
+    }
+    result
 }
 
 fn find_strings_with_distance_less_than<'a>(
@@ -93,3 +200,30 @@ fn distance(s1: &str, s2: &str) -> usize {
 
     distance
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const TEST_IDS: &str = "abcdef\nbababc\nabbcde\nabcccd\naabcdd\nabcdee\nababab\n";
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(12, checksum_for_ids(TEST_IDS.as_bytes()));
+        for chunk_size in 1..=8 {
+            assert_eq!(12, checksum_for_ids_parallel(TEST_IDS.as_bytes(), chunk_size));
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let pair = StringPair { s1: "fghij", s2: "fguij" };
+        assert_eq!("fghij\nfguij\n  ^", pair.diff(DiffStyle::Carets));
+        assert_eq!(
+            "fg\x1b[31mh\x1b[0mij\nfg\x1b[31mu\x1b[0mij",
+            pair.diff(DiffStyle::Colored)
+        );
+    }
+
+}
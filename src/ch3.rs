@@ -1,56 +1,703 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+
+use rayon::prelude::*;
+
 use crate::common::read_lines_from_file;
 
 pub fn ch3() {
     let claims = read_claims("ch3.txt");
-    println!("{}", count_overlapping_area(&claims));
+    println!("{}", count_overlapping_area(&claims, AreaMethod::Grid));
+    print!("{}", CoverageReport::of_claims(&claims));
     println!("{:?}", find_non_overlapping_claims(&claims));
 }
 
-const SIZE: usize = 1000;
 const CLAIM_THRESHOLD: usize = 2;
+/// Fabrics with a bounding box bigger than this are tracked sparsely
+const MAX_DENSE_AREA: usize = 16_000_000;
+/// Row bands marked in parallel per rayon thread, more bands even out the load of busy rows
+const BANDS_PER_THREAD: usize = 4;
+/// Claims spanning more buckets than this on a side are not put into `ClaimIndex` buckets
+const MAX_BUCKETS_PER_SIDE: usize = 4;
 
-fn count_overlapping_area(claims: &Vec<Claim>) -> usize {
-    let mut buf: Vec<usize> = vec![0; SIZE * SIZE];
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AreaMethod {
+    /// Marks every claimed square inch, cost grows with the total claimed area
+    Grid,
+    /// Same as `Grid`, but marks bands of fabric rows in parallel
+    ParallelGrid,
+    /// Sweeps over coordinate-compressed claim edges, cost grows with the claim count only
+    SweepLine,
+}
 
-    for c in claims {
-        c.mark(&mut buf);
+pub fn count_overlapping_area(claims: &[Claim], method: AreaMethod) -> usize {
+    area_covered_by_at_least(claims, CLAIM_THRESHOLD, method)
+}
+
+/// Area covered by at least `k` claims, `k` should be positive.
+pub fn area_covered_by_at_least(claims: &[Claim], k: usize, method: AreaMethod) -> usize {
+    assert!(k > 0, "Claim threshold should be positive");
+    match method {
+        AreaMethod::Grid => CoverageReport::of_claims(claims).area_covered_by_at_least(k),
+        AreaMethod::ParallelGrid => CoverageReport::of_claims_parallel(claims).area_covered_by_at_least(k),
+        AreaMethod::SweepLine => sweep_line_area_covered_by_at_least(claims, k),
+    }
+}
+
+/// Areas of the fabric covered by exactly 0, 1, 2, ... claims. Uncovered area is counted
+/// inside the bounding box of the claims only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoverageReport {
+    area_by_claim_count: Vec<usize>,
+}
+
+impl CoverageReport {
+    pub fn of_claims(claims: &[Claim]) -> CoverageReport {
+        CoverageReport::of_grid(&CoverageGrid::marked(claims), claims)
+    }
+
+    pub fn of_claims_parallel(claims: &[Claim]) -> CoverageReport {
+        CoverageReport::of_grid(&CoverageGrid::marked_parallel(claims), claims)
+    }
+
+    fn of_grid(grid: &CoverageGrid, claims: &[Claim]) -> CoverageReport {
+        let mut area_by_claim_count = vec![Bounds::of_claims(claims).area()];
+        for count in grid.covered_counts() {
+            if count >= area_by_claim_count.len() {
+                area_by_claim_count.resize(count + 1, 0);
+            }
+            area_by_claim_count[count] += 1;
+            area_by_claim_count[0] -= 1;
+        }
+        CoverageReport { area_by_claim_count }
     }
 
-    let mut count = 0;
-    for i in buf {
-        if i >= CLAIM_THRESHOLD {
-            count += 1;
+    pub fn max_claim_count(&self) -> usize {
+        self.area_by_claim_count.len() - 1
+    }
+
+    pub fn area_covered_by_exactly(&self, k: usize) -> usize {
+        self.area_by_claim_count.get(k).copied().unwrap_or(0)
+    }
+
+    pub fn area_covered_by_at_least(&self, k: usize) -> usize {
+        self.area_by_claim_count.iter().skip(k).sum()
+    }
+}
+
+impl fmt::Display for CoverageReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (count, area) in self.area_by_claim_count.iter().enumerate() {
+            writeln!(f, "{} claims: {} square inches", count, area)?;
         }
+        Ok(())
     }
+}
 
-    count
+struct SweepEvent {
+    y: usize,
+    opens: bool,
+    from_x_idx: usize,
+    to_x_idx: usize,
 }
 
-fn find_non_overlapping_claims<'a>(claims: &'a Vec<Claim>) -> Vec<&'a Claim> {
-    let mut overlap = vec![false; claims.len()];
-    for i in 0..(claims.len() - 1) {
-        for j in (i + 1)..claims.len() {
-            if claims[i].overlaps(&claims[j]) {
-                overlap[i] = true;
-                overlap[j] = true;
+/// Segment tree over the segments between consecutive distinct vertical edges. Every node keeps
+/// the number of claims spanning its whole range (never pushed down to the children) and, for
+/// every j in 0..=k, the width of its range covered by at least j claims.
+struct CoverageSegmentTree<'a> {
+    xs: &'a [usize],
+    k: usize,
+    cover: Vec<usize>,
+    covered_at_least: Vec<usize>,
+}
+
+impl<'a> CoverageSegmentTree<'a> {
+    fn new(xs: &'a [usize], k: usize) -> CoverageSegmentTree<'a> {
+        let nodes = 4 * xs.len().saturating_sub(1).max(1);
+        let mut tree = CoverageSegmentTree {
+            xs,
+            k,
+            cover: vec![0; nodes],
+            covered_at_least: vec![0; nodes * (k + 1)],
+        };
+        if xs.len() > 1 {
+            tree.build(1, 0, xs.len() - 1);
+        }
+        tree
+    }
+
+    fn build(&mut self, node: usize, from: usize, to: usize) {
+        if to - from > 1 {
+            let mid = (from + to) / 2;
+            self.build(2 * node, from, mid);
+            self.build(2 * node + 1, mid, to);
+        }
+        self.pull(node, from, to);
+    }
+
+    /// Adds (or removes) one claim spanning the segments from_idx..to_idx.
+    fn update(&mut self, from_idx: usize, to_idx: usize, opens: bool) {
+        if from_idx < to_idx {
+            self.update_node(1, 0, self.xs.len() - 1, from_idx, to_idx, opens);
+        }
+    }
+
+    fn update_node(&mut self, node: usize, from: usize, to: usize,
+                   from_idx: usize, to_idx: usize, opens: bool) {
+        if to_idx <= from || to <= from_idx {
+            return;
+        }
+        if from_idx <= from && to <= to_idx {
+            if opens {
+                self.cover[node] += 1;
+            } else {
+                self.cover[node] -= 1;
             }
+        } else {
+            let mid = (from + to) / 2;
+            self.update_node(2 * node, from, mid, from_idx, to_idx, opens);
+            self.update_node(2 * node + 1, mid, to, from_idx, to_idx, opens);
         }
+        self.pull(node, from, to);
     }
-    claims.iter().zip(overlap.iter())
-        .filter(|(_, o)| !**o)
-        .map(|(c, _)| c)
+
+    fn pull(&mut self, node: usize, from: usize, to: usize) {
+        let width = self.xs[to] - self.xs[from];
+        let cover = self.cover[node];
+        let is_leaf = to - from == 1;
+        for j in 0..=self.k {
+            let value = if j <= cover {
+                width
+            } else if is_leaf {
+                0
+            } else {
+                let (left, right) = (2 * node, 2 * node + 1);
+                self.covered_at_least[left * (self.k + 1) + j - cover]
+                    + self.covered_at_least[right * (self.k + 1) + j - cover]
+            };
+            self.covered_at_least[node * (self.k + 1) + j] = value;
+        }
+    }
+
+    /// Total width covered by at least k claims.
+    fn covered_width(&self) -> usize {
+        if self.xs.len() > 1 { self.covered_at_least[self.k + 1 + self.k] } else { 0 }
+    }
+}
+
+/// Moves a horizontal line down through top and bottom edges of the claims, keeping a segment
+/// tree over the distinct vertical edges. Takes O(n * k * log n) for n claims, regardless of
+/// their sizes.
+fn sweep_line_area_covered_by_at_least(claims: &[Claim], k: usize) -> usize {
+    let mut xs: Vec<usize> = claims.iter()
+        .flat_map(|c| vec![c.left_offset, c.right()])
+        .collect();
+    xs.sort_unstable();
+    xs.dedup();
+    let x_idx = |x: usize| xs.binary_search(&x).unwrap();
+
+    let mut events: Vec<SweepEvent> = Vec::with_capacity(claims.len() * 2);
+    for c in claims {
+        let (from_x_idx, to_x_idx) = (x_idx(c.left_offset), x_idx(c.right()));
+        events.push(SweepEvent { y: c.top_offset, opens: true, from_x_idx, to_x_idx });
+        events.push(SweepEvent { y: c.bottom(), opens: false, from_x_idx, to_x_idx });
+    }
+    // openings go first, so a zero-height claim is never closed before it is opened
+    events.sort_unstable_by_key(|e| (e.y, !e.opens));
+
+    let mut tree = CoverageSegmentTree::new(&xs, k);
+    let mut last_y = 0;
+    let mut area = 0;
+    for e in events {
+        area += tree.covered_width() * (e.y - last_y);
+        last_y = e.y;
+        tree.update(e.from_x_idx, e.to_x_idx, e.opens);
+    }
+
+    area
+}
+
+pub fn find_non_overlapping_claims(claims: &[Claim]) -> Vec<&Claim> {
+    let index = ClaimIndex::new(claims);
+    claims.iter().enumerate()
+        .filter(|&(i, c)| index.intersecting_indices(&c.bounds()).iter().all(|&j| j == i))
+        .map(|(_, c)| c)
         .collect()
 }
 
-fn read_claims(file_name: &str) -> Vec<Claim> {
-    read_lines_from_file(file_name)
-        .iter()
-        .map(|s| Claim::new(s))
+/// Claims grouped into square buckets of the fabric, so that only claims from the buckets
+/// touched by a rectangle have to be checked for intersection with it. Buckets are as big as
+/// the median claim, claims much bigger than that are kept aside and checked by every query.
+pub struct ClaimIndex<'a> {
+    claims: &'a [Claim],
+    bucket_size: usize,
+    buckets: HashMap<(usize, usize), Vec<usize>>,
+    oversized: Vec<usize>,
+}
+
+impl<'a> ClaimIndex<'a> {
+    pub fn new(claims: &'a [Claim]) -> ClaimIndex<'a> {
+        let mut sides: Vec<usize> = claims.iter().map(|c| c.width.max(c.height)).collect();
+        sides.sort_unstable();
+        let bucket_size = sides.get(sides.len() / 2).copied().unwrap_or(1).max(1);
+        let mut index = ClaimIndex { claims, bucket_size, buckets: HashMap::new(), oversized: Vec::new() };
+        for (i, c) in claims.iter().enumerate() {
+            if c.width.max(c.height) > bucket_size * MAX_BUCKETS_PER_SIDE {
+                index.oversized.push(i);
+                continue;
+            }
+            for bucket in index.buckets_of(&c.bounds()) {
+                index.buckets.entry(bucket).or_default().push(i);
+            }
+        }
+        index
+    }
+
+    /// Claims intersecting the rectangle, in the order they were given.
+    pub fn intersecting(&self, rect: &Bounds) -> Vec<&'a Claim> {
+        self.intersecting_indices(rect).into_iter()
+            .map(|i| &self.claims[i])
+            .collect()
+    }
+
+    /// Ids of claims intersecting the rectangle, in the order the claims were given.
+    pub fn ids_intersecting(&self, rect: &Bounds) -> Vec<usize> {
+        self.intersecting_indices(rect).into_iter()
+            .map(|i| self.claims[i].id)
+            .collect()
+    }
+
+    /// Ids of claims covering the square inch at (`x`, `y`).
+    pub fn ids_covering(&self, x: usize, y: usize) -> Vec<usize> {
+        self.ids_intersecting(&Bounds { left: x, top: y, right: x + 1, bottom: y + 1 })
+    }
+
+    /// A rectangle touching more buckets than there are claims is checked against every claim.
+    fn intersecting_indices(&self, rect: &Bounds) -> Vec<usize> {
+        let ((from_x, to_x), (from_y, to_y)) = self.bucket_ranges(rect);
+        let bucket_count = (to_x - from_x + 1).saturating_mul(to_y - from_y + 1);
+        let mut result: Vec<usize> = if bucket_count > self.claims.len() {
+            (0..self.claims.len()).filter(|&i| self.claims[i].bounds().intersects(rect)).collect()
+        } else {
+            self.buckets_of(rect)
+                .filter_map(|bucket| self.buckets.get(&bucket))
+                .flat_map(|claim_indices| claim_indices.iter().copied())
+                .chain(self.oversized.iter().copied())
+                .filter(|&i| self.claims[i].bounds().intersects(rect))
+                .collect()
+        };
+        result.sort_unstable();
+        result.dedup();
+        result
+    }
+
+    /// Buckets containing the rectangle, a rectangle without area is treated as one square inch wide.
+    fn buckets_of(&self, rect: &Bounds) -> impl Iterator<Item=(usize, usize)> {
+        let ((from_x, to_x), (from_y, to_y)) = self.bucket_ranges(rect);
+        (from_y..=to_y).flat_map(move |y| (from_x..=to_x).map(move |x| (x, y)))
+    }
+
+    /// Inclusive ranges of bucket columns and rows containing the rectangle.
+    fn bucket_ranges(&self, rect: &Bounds) -> ((usize, usize), (usize, usize)) {
+        let bucket_size = self.bucket_size;
+        (
+            (rect.left / bucket_size, (rect.right.max(rect.left + 1) - 1) / bucket_size),
+            (rect.top / bucket_size, (rect.bottom.max(rect.top + 1) - 1) / bucket_size),
+        )
+    }
+}
+
+/// Claims which can be added and removed one by one, keeping the overlapping area
+/// and non-overlapping claims up to date. Each change costs time proportional to the claim area.
+#[derive(Default)]
+pub struct Fabric {
+    claims: HashMap<usize, Claim>,
+    claim_ids_by_square: HashMap<(usize, usize), Vec<usize>>,
+    conflicting_ids: HashMap<usize, HashSet<usize>>,
+    non_overlapping_ids: BTreeSet<usize>,
+    overlapping_area: usize,
+}
+
+impl Fabric {
+    pub fn new() -> Fabric {
+        Fabric::default()
+    }
+
+    pub fn add(&mut self, claim: Claim) -> Result<(), String> {
+        if self.claims.contains_key(&claim.id) {
+            return Err(format!("Claim with id {} is already on the fabric", claim.id));
+        }
+        let mut conflicting_ids = HashSet::new();
+        for square in claim.squares() {
+            let ids = self.claim_ids_by_square.entry(square).or_default();
+            conflicting_ids.extend(ids.iter().copied());
+            ids.push(claim.id);
+            if ids.len() == CLAIM_THRESHOLD {
+                self.overlapping_area += 1;
+            }
+        }
+        for other_id in &conflicting_ids {
+            self.non_overlapping_ids.remove(other_id);
+            self.conflicting_ids.entry(*other_id).or_default().insert(claim.id);
+        }
+        if conflicting_ids.is_empty() {
+            self.non_overlapping_ids.insert(claim.id);
+        }
+        self.conflicting_ids.insert(claim.id, conflicting_ids);
+        self.claims.insert(claim.id, claim);
+        Ok(())
+    }
+
+    /// Removes the claim with given id, returning it if it was on the fabric.
+    pub fn remove(&mut self, id: usize) -> Option<Claim> {
+        let claim = self.claims.remove(&id)?;
+        for square in claim.squares() {
+            let ids = self.claim_ids_by_square.get_mut(&square).unwrap();
+            if ids.len() == CLAIM_THRESHOLD {
+                self.overlapping_area -= 1;
+            }
+            ids.retain(|&i| i != id);
+            if ids.is_empty() {
+                self.claim_ids_by_square.remove(&square);
+            }
+        }
+        for other_id in self.conflicting_ids.remove(&id).unwrap_or_default() {
+            let other_conflicting_ids = self.conflicting_ids.get_mut(&other_id).unwrap();
+            other_conflicting_ids.remove(&id);
+            if other_conflicting_ids.is_empty() {
+                self.non_overlapping_ids.insert(other_id);
+            }
+        }
+        self.non_overlapping_ids.remove(&id);
+        Some(claim)
+    }
+
+    /// Area covered by at least two claims.
+    pub fn overlapping_area(&self) -> usize {
+        self.overlapping_area
+    }
+
+    /// Ids of claims not overlapping any other claim, in ascending order.
+    pub fn non_overlapping_ids(&self) -> Vec<usize> {
+        self.non_overlapping_ids.iter().copied().collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Conflict {
+    pub first_id: usize,
+    pub second_id: usize,
+    pub overlap: Bounds,
+}
+
+/// Overlapping pairs of claims, with the rectangle shared by each pair.
+pub struct ConflictGraph {
+    conflicts: Vec<Conflict>,
+    conflicting_ids: HashMap<usize, Vec<usize>>,
+}
+
+impl ConflictGraph {
+    pub fn of_claims(claims: &[Claim]) -> ConflictGraph {
+        let index = ClaimIndex::new(claims);
+        let mut conflicts = Vec::new();
+        let mut conflicting_ids: HashMap<usize, Vec<usize>> = claims.iter().map(|c| (c.id, Vec::new())).collect();
+        for (i, c) in claims.iter().enumerate() {
+            for j in index.intersecting_indices(&c.bounds()).into_iter().filter(|&j| j > i) {
+                let other = &claims[j];
+                let overlap = c.bounds().intersection(&other.bounds()).unwrap();
+                conflicts.push(Conflict { first_id: c.id, second_id: other.id, overlap });
+                conflicting_ids.entry(c.id).or_default().push(other.id);
+                conflicting_ids.entry(other.id).or_default().push(c.id);
+            }
+        }
+        for ids in conflicting_ids.values_mut() {
+            ids.sort_unstable();
+        }
+        ConflictGraph { conflicts, conflicting_ids }
+    }
+
+    pub fn conflicts(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+
+    /// Ids of claims overlapping the claim with given id, empty for an unknown id.
+    pub fn conflicting_ids(&self, id: usize) -> &[usize] {
+        self.conflicting_ids.get(&id).map(|ids| ids.as_slice()).unwrap_or(&[])
+    }
+
+    /// One `first_id second_id @ left,top: widthxheight` line per conflicting pair.
+    pub fn edge_list(&self) -> String {
+        let mut result = String::new();
+        for c in &self.conflicts {
+            result.push_str(&format!(
+                "{} {} @ {},{}: {}x{}\n",
+                c.first_id, c.second_id, c.overlap.left, c.overlap.top, c.overlap.width(), c.overlap.height()
+            ));
+        }
+        result
+    }
+}
+
+/// Rectangle of the fabric, `right` and `bottom` are exclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub left: usize,
+    pub top: usize,
+    pub right: usize,
+    pub bottom: usize
+}
+
+impl Bounds {
+    fn of_claims(claims: &[Claim]) -> Bounds {
+        if claims.is_empty() {
+            return Bounds { left: 0, top: 0, right: 0, bottom: 0 };
+        }
+        Bounds {
+            left: claims.iter().map(|c| c.left_offset).min().unwrap(),
+            top: claims.iter().map(|c| c.top_offset).min().unwrap(),
+            right: claims.iter().map(|c| c.right()).max().unwrap(),
+            bottom: claims.iter().map(|c| c.bottom()).max().unwrap(),
+        }
+    }
+
+    fn width(&self) -> usize {
+        self.right - self.left
+    }
+
+    fn height(&self) -> usize {
+        self.bottom - self.top
+    }
+
+    fn area(&self) -> usize {
+        self.width().saturating_mul(self.height())
+    }
+
+    fn intersection(&self, other: &Bounds) -> Option<Bounds> {
+        if !self.intersects(other) {
+            return None;
+        }
+        Some(Bounds {
+            left: self.left.max(other.left),
+            top: self.top.max(other.top),
+            right: self.right.min(other.right),
+            bottom: self.bottom.min(other.bottom),
+        })
+    }
+
+    /// Rectangles intersect if they share at least one square inch, so a rectangle
+    /// without area intersects nothing.
+    fn intersects(&self, other: &Bounds) -> bool {
+        if self.area() == 0 || other.area() == 0 {
+            return false;
+        }
+        let other_lower = self.bottom <= other.top;
+        let this_above = other.bottom <= self.top;
+        let other_right = self.right <= other.left;
+        let this_left = other.right <= self.left;
+        !other_lower && !this_above && !other_right && !this_left
+    }
+}
+
+/// Number of claims covering each square inch of the fabric.
+#[derive(Debug, PartialEq)]
+enum CoverageGrid {
+    /// Row-major counters for every square inch inside `bounds`
+    Dense { bounds: Bounds, counts: Vec<usize> },
+    /// Counters only for square inches covered by at least one claim
+    Sparse(HashMap<(usize, usize), usize>),
+}
+
+impl CoverageGrid {
+    fn for_claims(claims: &[Claim]) -> CoverageGrid {
+        let bounds = Bounds::of_claims(claims);
+        if bounds.area() <= MAX_DENSE_AREA {
+            CoverageGrid::dense(bounds)
+        } else {
+            CoverageGrid::sparse()
+        }
+    }
+
+    fn dense(bounds: Bounds) -> CoverageGrid {
+        CoverageGrid::Dense { bounds, counts: vec![0; bounds.area()] }
+    }
+
+    fn sparse() -> CoverageGrid {
+        CoverageGrid::Sparse(HashMap::new())
+    }
+
+    fn marked(claims: &[Claim]) -> CoverageGrid {
+        let mut grid = CoverageGrid::for_claims(claims);
+        for c in claims {
+            grid.mark(c);
+        }
+        grid
+    }
+
+    /// Dense grid is split into bands of rows marked independently, sparse grid is marked
+    /// in per-thread maps which are merged afterwards.
+    fn marked_parallel(claims: &[Claim]) -> CoverageGrid {
+        match CoverageGrid::for_claims(claims) {
+            CoverageGrid::Dense { bounds, mut counts } => {
+                let width = bounds.width().max(1);
+                let band_height = (bounds.height() / (rayon::current_num_threads() * BANDS_PER_THREAD)).max(1);
+                counts.par_chunks_mut(band_height * width)
+                    .enumerate()
+                    .for_each(|(band, band_counts)| {
+                        let band_top = bounds.top + band * band_height;
+                        let band_bottom = band_top + band_counts.len() / width;
+                        for c in claims {
+                            for y in c.top_offset.max(band_top)..c.bottom().min(band_bottom) {
+                                let row = (y - band_top) * width;
+                                for x in c.left_offset..c.right() {
+                                    band_counts[row + x - bounds.left] += 1;
+                                }
+                            }
+                        }
+                    });
+                CoverageGrid::Dense { bounds, counts }
+            }
+            CoverageGrid::Sparse(_) => {
+                let counts = claims.par_iter()
+                    .fold(HashMap::new, |mut counts, c| {
+                        for square in c.squares() {
+                            *counts.entry(square).or_insert(0) += 1;
+                        }
+                        counts
+                    })
+                    .reduce(HashMap::new, |mut merged, counts| {
+                        for (square, count) in counts {
+                            *merged.entry(square).or_insert(0) += count;
+                        }
+                        merged
+                    });
+                CoverageGrid::Sparse(counts)
+            }
+        }
+    }
+
+    /// Panics for a dense grid if the claim doesn't fit into its bounds.
+    fn mark(&mut self, claim: &Claim) {
+        match self {
+            CoverageGrid::Dense { bounds, counts } => {
+                for y in claim.top_offset..claim.bottom() {
+                    let row = (y - bounds.top) * bounds.width();
+                    for x in claim.left_offset..claim.right() {
+                        counts[row + x - bounds.left] += 1;
+                    }
+                }
+            }
+            CoverageGrid::Sparse(counts) => {
+                for y in claim.top_offset..claim.bottom() {
+                    for x in claim.left_offset..claim.right() {
+                        *counts.entry((x, y)).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn count_at(&self, x: usize, y: usize) -> usize {
+        match self {
+            CoverageGrid::Dense { bounds, counts } => {
+                if x < bounds.left || x >= bounds.right || y < bounds.top || y >= bounds.bottom {
+                    0
+                } else {
+                    counts[(y - bounds.top) * bounds.width() + x - bounds.left]
+                }
+            }
+            CoverageGrid::Sparse(counts) => counts.get(&(x, y)).copied().unwrap_or(0),
+        }
+    }
+
+    /// Claim counts of all square inches covered by at least one claim.
+    fn covered_counts(&self) -> Box<dyn Iterator<Item=usize> + '_> {
+        match self {
+            CoverageGrid::Dense { counts, .. } => Box::new(counts.iter().copied().filter(|&c| c > 0)),
+            CoverageGrid::Sparse(counts) => Box::new(counts.values().copied()),
+        }
+    }
+}
+
+const UNCLAIMED_COLOUR: [u8; 3] = [0, 0, 0];
+const NON_OVERLAPPING_COLOUR: [u8; 3] = [0, 255, 0];
+
+pub fn write_heatmap_to_file(claims: &[Claim], file_name: &str, highlight_non_overlapping: bool) -> io::Result<()> {
+    let file = BufWriter::new(File::create(file_name)?);
+    write_heatmap(claims, highlight_non_overlapping, file)
+}
+
+/// Writes the bounding box of the claims as a binary PPM image, one pixel per square inch.
+/// Claimed square inches go from blue for a single claim to red for the most claimed ones,
+/// square inches of non-overlapping claims can be painted green instead. Fails with
+/// `InvalidInput` when the bounding box is larger than `MAX_DENSE_AREA` square inches.
+pub fn write_heatmap<W: Write>(claims: &[Claim], highlight_non_overlapping: bool, mut out: W) -> io::Result<()> {
+    let bounds = Bounds::of_claims(claims);
+    if bounds.area() > MAX_DENSE_AREA {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("heatmap of {}x{} pixels is too large", bounds.width(), bounds.height()),
+        ));
+    }
+    let grid = CoverageGrid::marked(claims);
+    let max_count = grid.covered_counts().max().unwrap_or(0);
+    let highlighted: Vec<Bounds> = if highlight_non_overlapping {
+        find_non_overlapping_claims(claims).iter().map(|c| c.bounds()).collect()
+    } else {
+        Vec::new()
+    };
+
+    write!(out, "P6\n{} {}\n255\n", bounds.width(), bounds.height())?;
+    let mut row = Vec::with_capacity(bounds.width() * 3);
+    let mut highlighted_mask = vec![false; bounds.width()];
+    for y in bounds.top..bounds.bottom {
+        row.clear();
+        highlighted_mask.iter_mut().for_each(|h| *h = false);
+        for b in highlighted.iter().filter(|b| b.top <= y && y < b.bottom) {
+            highlighted_mask[b.left - bounds.left..b.right - bounds.left].iter_mut().for_each(|h| *h = true);
+        }
+        for x in bounds.left..bounds.right {
+            let colour = if highlighted_mask[x - bounds.left] {
+                NON_OVERLAPPING_COLOUR
+            } else {
+                heatmap_colour(grid.count_at(x, y), max_count)
+            };
+            row.extend_from_slice(&colour);
+        }
+        out.write_all(&row)?;
+    }
+    out.flush()
+}
+
+fn heatmap_colour(count: usize, max_count: usize) -> [u8; 3] {
+    if count == 0 {
+        return UNCLAIMED_COLOUR;
+    }
+    let heat = if max_count > 1 { (count - 1) * 255 / (max_count - 1) } else { 0 } as u8;
+    [heat, 0, 255 - heat]
+}
+
+pub fn read_claims(file_name: &str) -> Vec<Claim> {
+    parse_claims(&read_lines_from_file(file_name))
+        .unwrap_or_else(|e| panic!("Error during parsing claims: {}", e))
+}
+
+/// Parses one claim per line, empty lines are skipped.
+pub fn parse_claims(lines: &[String]) -> Result<Vec<Claim>, String> {
+    lines.iter()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| Claim::parse(l).map_err(|e| format!("line {}: {}", i + 1, e)))
         .collect()
 }
 
-#[derive(Debug)]
-struct Claim {
+#[derive(Debug, Clone, Copy)]
+pub struct Claim {
     id: usize,
     left_offset: usize,
     top_offset: usize,
@@ -59,62 +706,114 @@ struct Claim {
 }
 
 impl Claim {
-    fn new(str: &str) -> Claim {
-        let mut chars = str.chars();
-        let id = parse_int(&mut chars).unwrap();
-        let left_offset = parse_int(&mut chars).unwrap();
-        let top_offset = parse_int(&mut chars).unwrap();
-        let width = parse_int(&mut chars).unwrap();
-        let height = parse_int(&mut chars).unwrap();
-
-        Claim { id, left_offset, top_offset, width, height }
-    }
-
-    fn mark(&self, buf: &mut Vec<usize>) {
-        for i in self.top_offset..(self.top_offset + self.height) {
-            for j in self.left_offset..(self.left_offset + self.width) {
-                let idx = i * SIZE + j;
-                buf[idx] += 1;
-            }
+    /// Parses a claim in the `#id @ left,top: widthxheight` form, surrounding whitespace is ignored.
+    pub fn parse(str: &str) -> Result<Claim, String> {
+        let mut parser = ClaimParser { str: str.trim(), pos: 0 };
+        parser.expect("#")?;
+        let id = parser.number("id")?;
+        parser.expect(" @ ")?;
+        let left_offset = parser.number("left offset")?;
+        parser.expect(",")?;
+        let top_offset = parser.number("top offset")?;
+        parser.expect(": ")?;
+        let width = parser.number("width")?;
+        parser.expect("x")?;
+        let height = parser.number("height")?;
+        parser.expect_end()?;
+
+        if left_offset.checked_add(width).is_none() || top_offset.checked_add(height).is_none() {
+            return Err(format!("Claim '{}' doesn't fit into the fabric", parser.str));
         }
+
+        Ok(Claim { id, left_offset, top_offset, width, height })
     }
 
-    fn overlaps(&self, other: &Claim) -> bool {
-        let other_lower = self.top_offset + self.height <= other.top_offset;
-        let this_above = other.top_offset + other.height <= self.top_offset;
-        let other_right = self.left_offset + self.width <= other.left_offset;
-        let this_left = other.left_offset + other.width <= self.left_offset;
-        !other_lower && !this_above && !other_right && !this_left
+    fn right(&self) -> usize {
+        self.left_offset + self.width
+    }
+
+    fn bottom(&self) -> usize {
+        self.top_offset + self.height
+    }
+
+    fn bounds(&self) -> Bounds {
+        Bounds { left: self.left_offset, top: self.top_offset, right: self.right(), bottom: self.bottom() }
+    }
+
+    fn squares(&self) -> impl Iterator<Item=(usize, usize)> {
+        let (left, right) = (self.left_offset, self.right());
+        (self.top_offset..self.bottom()).flat_map(move |y| (left..right).map(move |x| (x, y)))
+    }
+
+    pub fn overlaps(&self, other: &Claim) -> bool {
+        self.bounds().intersects(&other.bounds())
     }
 }
 
-fn parse_int(iter: &mut Iterator<Item=char>) -> Option<usize> {
-    let mut result: Option<usize> = None;
-    for c in iter.skip_while(|c| !c.is_digit(10)) {
-        match c {
-            '0'...'9' => {
-                let num = c.to_digit(10).unwrap() as usize;
-                result = match result {
-                    Some(n) => Some(n * 10 + num),
-                    None => Some(num)
-                }
-            }
-            _ => return result
+impl fmt::Display for Claim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{} @ {},{}: {}x{}", self.id, self.left_offset, self.top_offset, self.width, self.height)
+    }
+}
+
+struct ClaimParser<'a> {
+    str: &'a str,
+    pos: usize,
+}
+
+impl<'a> ClaimParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.str[self.pos..]
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            Ok(())
+        } else {
+            Err(self.error(&format!("'{}'", token)))
+        }
+    }
+
+    fn expect_end(&self) -> Result<(), String> {
+        if self.rest().is_empty() {
+            Ok(())
+        } else {
+            Err(self.error("end of claim"))
         }
     }
-    return result
+
+    fn number(&mut self, name: &str) -> Result<usize, String> {
+        let digits = self.rest().bytes().take_while(|b| b.is_ascii_digit()).count();
+        if digits == 0 {
+            return Err(self.error(name));
+        }
+        let number = self.rest()[..digits].parse::<usize>()
+            .map_err(|e| format!("Couldn't parse {} at column {} in claim '{}': {}", name, self.pos + 1, self.str, e))?;
+        self.pos += digits;
+        Ok(number)
+    }
+
+    fn error(&self, expected: &str) -> String {
+        let found = match self.rest().chars().next() {
+            Some(c) => format!("'{}'", c),
+            None => "end of claim".to_string(),
+        };
+        format!("Expected {} at column {} in claim '{}', found {}", expected, self.pos + 1, self.str, found)
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::common::SeededRandom;
 
     #[test]
     fn test_overlap() {
-        let c1 = Claim::new("#1 @ 1,3: 4x4");
-        let c2 = Claim::new("#2 @ 3,1: 4x4");
-        let c3 = Claim::new("#3 @ 5,5: 2x2");
+        let c1 = Claim::parse("#1 @ 1,3: 4x4").unwrap();
+        let c2 = Claim::parse("#2 @ 3,1: 4x4").unwrap();
+        let c3 = Claim::parse("#3 @ 5,5: 2x2").unwrap();
 
         assert!(c1.overlaps(&c2));
         assert!(c2.overlaps(&c1));
@@ -123,14 +822,274 @@ mod tests {
         assert!(!c2.overlaps(&c3));
         assert!(!c3.overlaps(&c2));
 
-        let c1 = Claim::new("#1 @ 0,0: 1x1");
-        let c2 = Claim::new("#2 @ 1,1: 1x1");
+        let c1 = Claim::parse("#1 @ 0,0: 1x1").unwrap();
+        let c2 = Claim::parse("#2 @ 1,1: 1x1").unwrap();
         assert!(!c1.overlaps(&c2));
         assert!(!c2.overlaps(&c1));
 
-        let c1 = Claim::new("#1 @ 0,1: 1x1");
-        let c2 = Claim::new("#2 @ 3,1: 1x1");
+        let c1 = Claim::parse("#1 @ 0,1: 1x1").unwrap();
+        let c2 = Claim::parse("#2 @ 3,1: 1x1").unwrap();
         assert!(!c1.overlaps(&c2));
     }
 
+    #[test]
+    fn test_parse() {
+        let claim = Claim::parse(" #123 @ 3,2: 5x4 ").unwrap();
+        assert_eq!("#123 @ 3,2: 5x4", claim.to_string());
+        assert_eq!(claim.to_string(), Claim::parse(&claim.to_string()).unwrap().to_string());
+
+        assert_eq!(
+            Err("Expected ': ' at column 9 in claim '#1 @ 1,3 4x4', found ' '".to_string()),
+            Claim::parse("#1 @ 1,3 4x4").map(|c| c.to_string())
+        );
+        assert!(Claim::parse("1 @ 1,3: 4x4").is_err());
+        assert!(Claim::parse("#1 @ 1,3: 4x4x").is_err());
+        assert!(Claim::parse("#1 @ -1,3: 4x4").is_err());
+        assert!(Claim::parse("#1 @ 1,3: 4x").is_err());
+        assert!(Claim::parse("#1 @ 1,99999999999999999999999: 4x4").is_err());
+
+        let lines: Vec<String> = ["#1 @ 1,3: 4x4", "", "#3 @ 5,5 2x2"].iter().map(|l| l.to_string()).collect();
+        let error = parse_claims(&lines).map(|_| ()).unwrap_err();
+        assert!(error.starts_with("line 3: "), "{}", error);
+        assert_eq!(1, parse_claims(&lines[..2]).unwrap().len());
+    }
+
+    fn get_test_claims() -> Vec<Claim> {
+        vec![
+            Claim::parse("#1 @ 1,3: 4x4").unwrap(),
+            Claim::parse("#2 @ 3,1: 4x4").unwrap(),
+            Claim::parse("#3 @ 5,5: 2x2").unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_overlapping_area() {
+        assert_eq!(4, count_overlapping_area(&get_test_claims(), AreaMethod::Grid));
+
+        let shifted_claims: Vec<_> = get_test_claims().iter()
+            .map(|c| Claim { left_offset: c.left_offset + 5_000_000, top_offset: c.top_offset + 5_000_000, ..*c })
+            .collect();
+        assert_eq!(36, Bounds::of_claims(&shifted_claims).area());
+        assert_eq!(4, count_overlapping_area(&shifted_claims, AreaMethod::Grid));
+
+        let mut scattered_claims = get_test_claims();
+        scattered_claims.push(Claim::parse("#4 @ 5000000,5000000: 2x2").unwrap());
+        assert!(Bounds::of_claims(&scattered_claims).area() > MAX_DENSE_AREA);
+        assert_eq!(4, count_overlapping_area(&scattered_claims, AreaMethod::Grid));
+        assert_eq!(4, count_overlapping_area(&scattered_claims, AreaMethod::SweepLine));
+        assert_eq!(0, count_overlapping_area(&[], AreaMethod::Grid));
+        assert_eq!(0, count_overlapping_area(&[], AreaMethod::SweepLine));
+    }
+
+    fn get_pseudo_random_claims(count: usize) -> Vec<Claim> {
+        let mut random = SeededRandom::new(42);
+        let mut next = |modulo: usize| random.below(modulo);
+        (1..=count)
+            .map(|id| Claim { id, left_offset: next(100), top_offset: next(100), width: next(30), height: next(30) })
+            .collect()
+    }
+
+    #[test]
+    fn test_coverage_report() {
+        let report = CoverageReport::of_claims(&get_test_claims());
+        assert_eq!(2, report.max_claim_count());
+        assert_eq!(4, report.area_covered_by_exactly(0));
+        assert_eq!(28, report.area_covered_by_exactly(1));
+        assert_eq!(4, report.area_covered_by_exactly(2));
+        assert_eq!(0, report.area_covered_by_exactly(3));
+        assert_eq!(36, report.area_covered_by_at_least(0));
+        assert_eq!(32, report.area_covered_by_at_least(1));
+        assert_eq!(4, report.area_covered_by_at_least(2));
+        assert_eq!(0, report.area_covered_by_at_least(3));
+        assert_eq!(
+            "0 claims: 4 square inches\n1 claims: 28 square inches\n2 claims: 4 square inches\n",
+            report.to_string()
+        );
+    }
+
+    #[test]
+    fn test_conflict_graph() {
+        let mut claims = get_test_claims();
+        claims.push(Claim::parse("#4 @ 2,2: 2x2").unwrap());
+        let graph = ConflictGraph::of_claims(&claims);
+        assert_eq!(&[2, 4], graph.conflicting_ids(1));
+        assert_eq!(&[1, 4], graph.conflicting_ids(2));
+        assert!(graph.conflicting_ids(3).is_empty());
+        assert_eq!(
+            Some(&Conflict { first_id: 1, second_id: 2, overlap: Bounds { left: 3, top: 3, right: 5, bottom: 5 } }),
+            graph.conflicts().first()
+        );
+        assert_eq!("1 2 @ 3,3: 2x2\n1 4 @ 2,3: 2x1\n2 4 @ 3,2: 1x2\n", graph.edge_list());
+    }
+
+    #[test]
+    fn test_non_overlapping_claims() {
+        let claims = get_test_claims();
+        let non_overlapping: Vec<_> = find_non_overlapping_claims(&claims).iter().map(|c| c.id).collect();
+        assert_eq!(vec![3], non_overlapping);
+        assert!(find_non_overlapping_claims(&[]).is_empty());
+
+        let claims = get_pseudo_random_claims(300);
+        let index = ClaimIndex::new(&claims);
+        for c in &claims {
+            let expected: Vec<_> = claims.iter().filter(|o| o.overlaps(c)).map(|o| o.id).collect();
+            let found: Vec<_> = index.intersecting(&c.bounds()).iter().map(|o| o.id).collect();
+            assert_eq!(expected, found);
+        }
+    }
+
+    #[test]
+    fn test_claim_index_with_skewed_claims() {
+        let mut claims: Vec<Claim> = (0..20_000)
+            .map(|i| Claim { id: i + 1, left_offset: (i % 200) * 3, top_offset: (i / 200) * 3, width: 1, height: 1 })
+            .collect();
+        claims.push(Claim { id: 20_001, left_offset: 150, top_offset: 150, width: 200_000, height: 200_000 });
+        let index = ClaimIndex::new(&claims);
+        assert_eq!(1, index.bucket_size);
+        assert_eq!(vec![20_000], index.oversized);
+
+        let huge = &claims[20_000];
+        let covered: Vec<_> = claims.iter().filter(|c| c.overlaps(huge)).map(|c| c.id).collect();
+        assert_eq!(covered, index.ids_intersecting(&huge.bounds()));
+        assert_eq!(vec![20_001], index.ids_covering(1000, 1000));
+        assert_eq!(vec![1], index.ids_covering(0, 0));
+        assert_eq!(claims.len() - covered.len(), find_non_overlapping_claims(&claims).len());
+    }
+
+    #[test]
+    fn test_point_queries() {
+        let claims = get_test_claims();
+        let index = ClaimIndex::new(&claims);
+        assert_eq!(vec![1, 2], index.ids_covering(3, 3));
+        assert_eq!(vec![2], index.ids_covering(6, 1));
+        assert_eq!(vec![3], index.ids_covering(5, 5));
+        assert!(index.ids_covering(0, 0).is_empty());
+        assert!(index.ids_covering(1000, 1000).is_empty());
+        assert_eq!(vec![1, 3], index.ids_intersecting(&Bounds { left: 0, top: 5, right: 10, bottom: 10 }));
+
+        let claims = get_pseudo_random_claims(300);
+        let index = ClaimIndex::new(&claims);
+        for (x, y) in (0..130).step_by(7).flat_map(|x| (0..130).step_by(5).map(move |y| (x, y))) {
+            let expected: Vec<_> = claims.iter()
+                .filter(|c| c.left_offset <= x && x < c.right() && c.top_offset <= y && y < c.bottom())
+                .map(|c| c.id)
+                .collect();
+            assert_eq!(expected, index.ids_covering(x, y));
+        }
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let claims = get_test_claims();
+        let mut image = Vec::new();
+        write_heatmap(&claims, false, &mut image).unwrap();
+        let header = b"P6\n6 6\n255\n";
+        assert_eq!(header, &image[..header.len()]);
+        assert_eq!(header.len() + 6 * 6 * 3, image.len());
+        let pixel = |x: usize, y: usize| {
+            let offset = header.len() + ((y - 1) * 6 + x - 1) * 3;
+            [image[offset], image[offset + 1], image[offset + 2]]
+        };
+        assert_eq!(UNCLAIMED_COLOUR, pixel(1, 1));
+        assert_eq!([0, 0, 255], pixel(1, 3));
+        assert_eq!([255, 0, 0], pixel(3, 3));
+        assert_eq!([0, 0, 255], pixel(5, 5));
+
+        let mut highlighted_image = Vec::new();
+        write_heatmap(&claims, true, &mut highlighted_image).unwrap();
+        let offset = header.len() + (4 * 6 + 4) * 3;
+        assert_eq!(NON_OVERLAPPING_COLOUR, highlighted_image[offset..offset + 3]);
+        assert_eq!(image[..offset], highlighted_image[..offset]);
+
+        let mut scattered_claims = claims.clone();
+        scattered_claims.push(Claim::parse("#4 @ 5000000,5000000: 2x2").unwrap());
+        let error = write_heatmap(&scattered_claims, true, Vec::new()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    }
+
+    #[test]
+    fn test_fabric() {
+        let mut fabric = Fabric::new();
+        for c in get_test_claims() {
+            fabric.add(c).unwrap();
+        }
+        assert_eq!(4, fabric.overlapping_area());
+        assert_eq!(vec![3], fabric.non_overlapping_ids());
+        assert!(fabric.add(Claim::parse("#3 @ 0,0: 1x1").unwrap()).is_err());
+
+        let removed = fabric.remove(2).unwrap();
+        assert_eq!(2, removed.id);
+        assert!(fabric.remove(2).is_none());
+        assert_eq!(0, fabric.overlapping_area());
+        assert_eq!(vec![1, 3], fabric.non_overlapping_ids());
+
+        fabric.add(Claim::parse("#4 @ 4,4: 2x2").unwrap()).unwrap();
+        assert_eq!(3, fabric.overlapping_area());
+        assert!(fabric.non_overlapping_ids().is_empty());
+
+        let claims = get_pseudo_random_claims(100);
+        let mut fabric = Fabric::new();
+        for c in &claims {
+            fabric.add(*c).unwrap();
+        }
+        for removed in 0..claims.len() {
+            let left = &claims[removed..];
+            assert_eq!(count_overlapping_area(left, AreaMethod::SweepLine), fabric.overlapping_area());
+            let non_overlapping: Vec<_> = find_non_overlapping_claims(left).iter().map(|c| c.id).collect();
+            assert_eq!(non_overlapping, fabric.non_overlapping_ids());
+            fabric.remove(claims[removed].id);
+        }
+        assert_eq!(0, fabric.overlapping_area());
+    }
+
+    #[test]
+    fn test_parallel_marking() {
+        let claims = get_pseudo_random_claims(500);
+        assert_eq!(CoverageGrid::marked(&claims), CoverageGrid::marked_parallel(&claims));
+        assert_eq!(CoverageReport::of_claims(&claims), CoverageReport::of_claims_parallel(&claims));
+
+        let mut scattered_claims = get_pseudo_random_claims(50);
+        scattered_claims.push(Claim::parse("#51 @ 5000000,5000000: 2x2").unwrap());
+        assert_eq!(CoverageGrid::marked(&scattered_claims), CoverageGrid::marked_parallel(&scattered_claims));
+        assert_eq!(CoverageGrid::marked(&[]), CoverageGrid::marked_parallel(&[]));
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_parallel_marking() {
+        use std::time::Instant;
+
+        let claims: Vec<_> = get_pseudo_random_claims(20_000).iter()
+            .map(|c| Claim { left_offset: c.left_offset * 20, top_offset: c.top_offset * 20, width: c.width * 5, height: c.height * 5, ..*c })
+            .collect();
+
+        let start = Instant::now();
+        let sequential = CoverageGrid::marked(&claims);
+        let sequential_time = start.elapsed();
+
+        let start = Instant::now();
+        let parallel = CoverageGrid::marked_parallel(&claims);
+        let parallel_time = start.elapsed();
+
+        assert_eq!(sequential, parallel);
+        println!(
+            "{} threads, sequential: {:?}, parallel: {:?}, speedup: {:.2}",
+            rayon::current_num_threads(),
+            sequential_time,
+            parallel_time,
+            sequential_time.as_secs_f64() / parallel_time.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn test_sweep_line_matches_grid() {
+        let claims = get_pseudo_random_claims(200);
+        for k in 1..6 {
+            let grid_area = area_covered_by_at_least(&claims, k, AreaMethod::Grid);
+            assert_eq!(grid_area, area_covered_by_at_least(&claims, k, AreaMethod::ParallelGrid));
+            assert_eq!(grid_area, area_covered_by_at_least(&claims, k, AreaMethod::SweepLine));
+        }
+    }
+
 }
\ No newline at end of file
@@ -1,141 +1,773 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::mem;
 
 use chrono::prelude::*;
+use chrono::Duration;
 
-use crate::common::read_lines_from_file;
-
+/// Runs strategies named in the command line arguments, or both puzzle strategies if none is given.
 pub fn ch4() {
     let events = read_events_from_file_sorted("ch4.txt");
-    for e in &events {
-        println!("{:?}", e);
-    }
+    print!("{}", timeline_chart(&build_timeline(&events)));
     let sleeps_grouped = build_sleeps_grouped_by_guard(&events).unwrap_or_else(|e| panic!("Error during processing events: {}", e));
-    
-    strategy1(&sleeps_grouped);
-    strategy2(&sleeps_grouped);
+
+    let mut names: Vec<String> = env::args().skip(1).collect();
+    if names.is_empty() {
+        names = vec!["most-slept-guard".to_string(), "most-slept-minute".to_string()];
+    }
+    for name in names {
+        let strategy = strategy_by_name(&name)
+            .unwrap_or_else(|| panic!("Unknown strategy {}, expected one of: {}", name, STRATEGY_NAMES.join(", ")));
+        match strategy.select(&sleeps_grouped) {
+            Some(r) => println!("{}: {} * {} = {} (score {})", name, r.minute, r.guard_id, r.answer(), r.score),
+            None => println!("{}: no guard ever slept", name),
+        }
+    }
 }
 
-fn strategy1(sleeps_grouped: &HashMap<usize, Vec<Sleep>>) {
-    let (guard_id, sleeps) = find_guard_with_most_slept_amount(sleeps_grouped);
-    
-    let (most_slept_minute_for_guard_with_most_sleep, _) = find_most_slept_minute(sleeps);
-    println!("Strategy 1: {} * {} = {}", most_slept_minute_for_guard_with_most_sleep, guard_id, most_slept_minute_for_guard_with_most_sleep * guard_id);
+const STRATEGY_NAMES: [&str; 5] = [
+    "most-slept-guard",
+    "most-slept-minute",
+    "most-nights-asleep",
+    "longest-nap",
+    "most-consistent-minute",
+];
+
+pub fn strategy_by_name(name: &str) -> Option<Box<dyn GuardStrategy>> {
+    match name {
+        "most-slept-guard" => Some(Box::new(MostSleptGuard)),
+        "most-slept-minute" => Some(Box::new(MostSleptMinute)),
+        "most-nights-asleep" => Some(Box::new(MostNightsAsleep)),
+        "longest-nap" => Some(Box::new(LongestNap)),
+        "most-consistent-minute" => Some(Box::new(MostConsistentMinute)),
+        _ => None,
+    }
 }
 
-fn find_guard_with_most_slept_amount<'a>(sleeps_grouped: &'a HashMap<usize, Vec<Sleep>>) -> (&'a usize, &'a Vec<Sleep>) {
-    sleeps_grouped.iter()
-        .max_by_key(|&(_, sleeps)| sleeps.iter().map(|s| s.len()).sum::<usize>())
-        .unwrap_or_else(|| panic!("Not single guard was in event log"))
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StrategyResult {
+    pub guard_id: usize,
+    /// Minute of the day, 0 is 00:00
+    pub minute: usize,
+    /// What the strategy maximized, e.g. total minutes asleep
+    pub score: usize,
 }
 
-fn strategy2(sleeps_grouped: &HashMap<usize, Vec<Sleep>>) {
-    let (guard_id, most_slept_minute) = fing_guard_with_most_slept_minute(sleeps_grouped);
-    println!("Strategy 2: {} * {} = {}", most_slept_minute, guard_id, guard_id * most_slept_minute);
+impl StrategyResult {
+    pub fn answer(&self) -> usize {
+        self.guard_id * self.minute
+    }
+}
+
+pub trait GuardStrategy {
+    /// Selects a guard and a minute to sneak in, `None` if no guard ever slept.
+    fn select(&self, sleeps_grouped: &HashMap<usize, Vec<Sleep>>) -> Option<StrategyResult>;
 }
 
-fn fing_guard_with_most_slept_minute<'a>(sleeps_grouped: &'a HashMap<usize, Vec<Sleep>>) -> (&'a usize, usize) {
+/// Picks the guard with the highest score, the lowest id wins a tie, then their most slept minute.
+fn select_guard_by_score<F>(sleeps_grouped: &HashMap<usize, Vec<Sleep>>, score: F) -> Option<StrategyResult>
+    where F: Fn(&[Sleep]) -> usize
+{
     sleeps_grouped.iter()
-        .map(&|(guard_id, sleeps)| (guard_id, find_most_slept_minute(sleeps)))
-        .max_by_key(|&(_, (_, slept))| slept)
-        .map(|(guard_id, (most_slept_minute, _))| (guard_id, most_slept_minute))
-        .unwrap_or_else(|| panic!("Not single guard was in event log"))
+        .map(|(guard_id, sleeps)| (*guard_id, score(sleeps)))
+        .max_by_key(|&(guard_id, score)| (score, Reverse(guard_id)))
+        .map(|(guard_id, score)| {
+            let (minute, _) = find_most_slept_minute(&sleeps_grouped[&guard_id]);
+            StrategyResult { guard_id, minute, score }
+        })
+}
+
+/// Puzzle strategy 1: the guard with most minutes asleep and the minute they slept most.
+pub struct MostSleptGuard;
+
+impl GuardStrategy for MostSleptGuard {
+    fn select(&self, sleeps_grouped: &HashMap<usize, Vec<Sleep>>) -> Option<StrategyResult> {
+        select_guard_by_score(sleeps_grouped, |sleeps| sleeps.iter().map(|s| s.len()).sum())
+    }
+}
+
+/// Puzzle strategy 2: the guard most frequently asleep on the same minute.
+pub struct MostSleptMinute;
+
+impl GuardStrategy for MostSleptMinute {
+    fn select(&self, sleeps_grouped: &HashMap<usize, Vec<Sleep>>) -> Option<StrategyResult> {
+        sleeps_grouped.iter()
+            .map(|(guard_id, sleeps)| (*guard_id, find_most_slept_minute(sleeps)))
+            .max_by_key(|&(guard_id, (_, slept))| (slept, Reverse(guard_id)))
+            .map(|(guard_id, (minute, score))| StrategyResult { guard_id, minute, score })
+    }
 }
 
-const MINUTES: usize = 60;
+/// The guard who slept on most nights and the minute they slept most.
+pub struct MostNightsAsleep;
+
+impl GuardStrategy for MostNightsAsleep {
+    fn select(&self, sleeps_grouped: &HashMap<usize, Vec<Sleep>>) -> Option<StrategyResult> {
+        select_guard_by_score(sleeps_grouped, |sleeps| sleeps.iter().map(|s| s.night()).collect::<HashSet<_>>().len())
+    }
+}
+
+/// The guard with the longest single nap and the minute that nap began.
+pub struct LongestNap;
+
+impl GuardStrategy for LongestNap {
+    fn select(&self, sleeps_grouped: &HashMap<usize, Vec<Sleep>>) -> Option<StrategyResult> {
+        sleeps_grouped.iter()
+            .filter_map(|(guard_id, sleeps)| sleeps.iter().max_by_key(|s| (s.len(), Reverse(s.from))).map(|s| (*guard_id, s)))
+            .max_by_key(|&(guard_id, s)| (s.len(), Reverse(guard_id)))
+            .map(|(guard_id, s)| StrategyResult { guard_id, minute: s.first_minute_of_day(), score: s.len() })
+    }
+}
+
+/// The guard asleep on the same minute on most distinct nights, unlike `MostSleptMinute`
+/// a sleep lasting for days counts once.
+pub struct MostConsistentMinute;
+
+impl GuardStrategy for MostConsistentMinute {
+    fn select(&self, sleeps_grouped: &HashMap<usize, Vec<Sleep>>) -> Option<StrategyResult> {
+        sleeps_grouped.iter()
+            .map(|(guard_id, sleeps)| (*guard_id, find_most_consistent_minute(sleeps)))
+            .max_by_key(|&(guard_id, (_, nights))| (nights, Reverse(guard_id)))
+            .map(|(guard_id, (minute, score))| StrategyResult { guard_id, minute, score })
+    }
+}
+
+const MINUTES_IN_DAY: usize = 24 * 60;
+
+/// Minute of the day (0 is 00:00) slept most often, with the number of times it was slept.
+fn find_most_slept_minute(sleeps: &[Sleep]) -> (usize, usize) {
+    most_frequent_minute(&minutes_histogram(sleeps))
+}
 
-fn find_most_slept_minute(sleeps: &Vec<Sleep>) -> (usize, usize) {
-    let mut buf: Vec<usize> = vec![0; MINUTES];
+/// Number of times each minute of the day was slept.
+fn minutes_histogram(sleeps: &[Sleep]) -> Vec<usize> {
+    let mut buf: Vec<usize> = vec![0; MINUTES_IN_DAY];
     for s in sleeps {
-        for i in s.from..(s.to + 1) {
-            buf[i] += 1;
+        for minute in s.minutes_of_day() {
+            buf[minute] += 1;
         }
     }
-    buf.into_iter().enumerate().max_by_key(|(_, slept)| *slept).unwrap()
+    buf
+}
+
+fn most_frequent_minute(histogram: &[usize]) -> (usize, usize) {
+    histogram.iter().copied().enumerate().max_by_key(|&(minute, slept)| (slept, Reverse(minute))).unwrap()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GuardStats {
+    pub guard_id: usize,
+    pub total_minutes_asleep: usize,
+    /// Shifts the guard was on duty, whether they slept or not
+    pub shifts: usize,
+    /// Number of times each minute of the day was slept
+    pub minutes_histogram: Vec<usize>,
+    pub longest_nap: usize,
+    pub average_nap: f64,
+    /// Night of the first shift of the guard
+    pub first_seen: NaiveDate,
+    /// Night of the last shift of the guard
+    pub last_seen: NaiveDate,
+}
+
+impl GuardStats {
+    /// `None` if the guard had no shifts.
+    pub fn new(guard_id: usize, sleeps: &[Sleep], shift_nights: &[NaiveDate]) -> Option<GuardStats> {
+        if shift_nights.is_empty() {
+            return None;
+        }
+        let total_minutes_asleep: usize = sleeps.iter().map(|s| s.len()).sum();
+        let average_nap = if sleeps.is_empty() { 0.0 } else { total_minutes_asleep as f64 / sleeps.len() as f64 };
+        Some(GuardStats {
+            guard_id,
+            total_minutes_asleep,
+            shifts: shift_nights.len(),
+            minutes_histogram: minutes_histogram(sleeps),
+            longest_nap: sleeps.iter().map(|s| s.len()).max().unwrap_or(0),
+            average_nap,
+            first_seen: *shift_nights.iter().min().unwrap(),
+            last_seen: *shift_nights.iter().max().unwrap(),
+        })
+    }
+
+    pub fn most_slept_minute(&self) -> usize {
+        most_frequent_minute(&self.minutes_histogram).0
+    }
+}
+
+/// Nights of the shifts of every guard in the events, in order and without repeats.
+pub fn shift_nights_by_guard(events: &[Event]) -> HashMap<usize, Vec<NaiveDate>> {
+    let mut shift_nights: HashMap<usize, Vec<NaiveDate>> = HashMap::new();
+    for e in events {
+        if let EventType::ShiftBegin(guard_id) = e.e_type {
+            shift_nights.entry(guard_id).or_default().push(night_of(e.date));
+        }
+    }
+    for nights in shift_nights.values_mut() {
+        nights.sort();
+        nights.dedup();
+    }
+    shift_nights
+}
+
+/// Stats of every guard who had a shift, ordered by guard id. Sleeps come from
+/// `build_sleeps_grouped_by_guard` (or its lenient version), shifts from `shift_nights_by_guard`.
+pub fn build_guard_stats(sleeps_grouped: &HashMap<usize, Vec<Sleep>>,
+                         shift_nights: &HashMap<usize, Vec<NaiveDate>>) -> Vec<GuardStats> {
+    let mut stats: Vec<GuardStats> = shift_nights.iter()
+        .filter_map(|(guard_id, nights)| {
+            let sleeps = sleeps_grouped.get(guard_id).map_or(&[][..], |s| &s[..]);
+            GuardStats::new(*guard_id, sleeps, nights)
+        })
+        .collect();
+    stats.sort_by_key(|s| s.guard_id);
+    stats
+}
+
+/// One line per guard, the histogram is summarized by the most slept minute, see `minutes_histogram_csv`.
+pub fn guard_stats_csv(stats: &[GuardStats]) -> String {
+    let mut csv = "guard_id,total_minutes_asleep,shifts,longest_nap,average_nap,first_seen,last_seen,most_slept_minute\n".to_string();
+    for s in stats {
+        csv.push_str(&format!(
+            "{},{},{},{},{:.2},{},{},{}\n",
+            s.guard_id,
+            s.total_minutes_asleep,
+            s.shifts,
+            s.longest_nap,
+            s.average_nap,
+            s.first_seen.format("%Y-%m-%d"),
+            s.last_seen.format("%Y-%m-%d"),
+            s.most_slept_minute()
+        ));
+    }
+    csv
+}
+
+/// One line per guard and minute of the day the guard slept at least once.
+pub fn minutes_histogram_csv(stats: &[GuardStats]) -> String {
+    let mut csv = "guard_id,minute,times_slept\n".to_string();
+    for s in stats {
+        for (minute, times_slept) in s.minutes_histogram.iter().enumerate().filter(|&(_, t)| *t > 0) {
+            csv.push_str(&format!("{},{},{}\n", s.guard_id, minute, times_slept));
+        }
+    }
+    csv
+}
+
+/// Minute of the day slept on most distinct nights, with the number of those nights.
+fn find_most_consistent_minute(sleeps: &[Sleep]) -> (usize, usize) {
+    let minutes_of_nights: HashSet<(usize, NaiveDate)> = sleeps.iter()
+        .flat_map(|s| s.minutes_of_day().map(move |minute| (minute, s.night())))
+        .collect();
+    let mut buf: Vec<usize> = vec![0; MINUTES_IN_DAY];
+    for (minute, _) in minutes_of_nights {
+        buf[minute] += 1;
+    }
+    buf.into_iter().enumerate().max_by_key(|&(minute, nights)| (nights, Reverse(minute))).unwrap()
+}
+
+/// Fails with every anomaly found in the events, see `build_sleeps_grouped_by_guard_lenient`.
+pub fn build_sleeps_grouped_by_guard(events: &[Event]) -> Result<HashMap<usize, Vec<Sleep>>, String> {
+    let (sleeps_grouped, anomalies) = build_sleeps_grouped_by_guard_lenient(events);
+    if anomalies.is_empty() {
+        Ok(sleeps_grouped)
+    } else {
+        let descriptions: Vec<String> = anomalies.iter().map(|a| a.to_string()).collect();
+        Err(format!("{} anomalies in guard log:\n{}", anomalies.len(), descriptions.join("\n")))
+    }
+}
+
+/// Reports every anomaly found in the sorted events.
+pub fn validate_events(events: &[Event]) -> Vec<Anomaly> {
+    build_sleeps_grouped_by_guard_lenient(events).1
 }
 
-fn build_sleeps_grouped_by_guard(events: &Vec<Event>) -> Result<HashMap<usize, Vec<Sleep>>, String> {
-    let mut sleeps_grouped = HashMap::new();
-    let mut sleep_state = PartialSleepState::NoGuard;
+/// Never fails, events which make no sense are skipped and sleeps left unterminated are closed
+/// by the next shift or, at the end of the log, at the start of the next hour.
+pub fn build_sleeps_grouped_by_guard_lenient(events: &[Event]) -> (HashMap<usize, Vec<Sleep>>, Vec<Anomaly>) {
+    let mut sleeps_grouped: HashMap<usize, Vec<Sleep>> = HashMap::new();
+    let mut processor = GuardLogProcessor::new();
     for e in events {
+        if let Some((guard_id, sleep)) = processor.process(e) {
+            sleeps_grouped.entry(guard_id).or_default().push(sleep);
+        }
+    }
+    let (last_sleep, anomalies) = processor.finish();
+    if let Some((guard_id, sleep)) = last_sleep {
+        sleeps_grouped.entry(guard_id).or_default().push(sleep);
+    }
+    (sleeps_grouped, anomalies)
+}
+
+/// Turns sorted events, fed one at a time, into sleeps of guards. Like
+/// `build_sleeps_grouped_by_guard_lenient` it skips events which make no sense, collecting anomalies.
+pub struct GuardLogProcessor {
+    sleep_state: PartialSleepState,
+    previous: Option<Event>,
+    shift_night: Option<NaiveDate>,
+    anomalies: Vec<Anomaly>,
+}
+
+impl Default for GuardLogProcessor {
+    fn default() -> GuardLogProcessor {
+        GuardLogProcessor::new()
+    }
+}
+
+impl GuardLogProcessor {
+    pub fn new() -> GuardLogProcessor {
+        GuardLogProcessor {
+            sleep_state: PartialSleepState::NoGuard,
+            previous: None,
+            shift_night: None,
+            anomalies: Vec::new(),
+        }
+    }
+
+    pub fn state(&self) -> &PartialSleepState {
+        &self.sleep_state
+    }
+
+    /// Anomalies found since the last call.
+    pub fn take_anomalies(&mut self) -> Vec<Anomaly> {
+        mem::take(&mut self.anomalies)
+    }
+
+    /// Returns the sleep ended by the event, with the id of the guard who slept.
+    pub fn process(&mut self, e: &Event) -> Option<(usize, Sleep)> {
+        if let Some(previous) = &self.previous {
+            if previous.date == e.date {
+                let is_repeated = previous.e_type == e.e_type;
+                self.report(AnomalyKind::DuplicateTimestamp, e);
+                if is_repeated {
+                    return None;
+                }
+            }
+        }
+        self.previous = Some(e.clone());
+
         match e.e_type {
-            EventType::ShiftBegin(guard_id) => match sleep_state {
-                PartialSleepState::NoGuard | PartialSleepState::NotSleeping(_) => sleep_state = PartialSleepState::NotSleeping(guard_id),
-                _ => return unexpected_state_for_event(e, &sleep_state)
-            },
-            EventType::WakeUp => match sleep_state {
-                PartialSleepState::Sleeping(guard_id, from) => {
-                    sleep_state = PartialSleepState::NotSleeping(guard_id);
-                    let to = if e.date.minute() > 0 {
-                        (e.date.minute() - 1) as usize
-                    } else {
-                        return Err("Woke up at 0 minute".to_string())
-                    };
-                    let sleep = Sleep { from, to };
-                    let sleeps = sleeps_grouped.entry(guard_id).or_insert(Vec::new());
-                    (*sleeps).push(sleep);
+            EventType::ShiftBegin(guard_id) => {
+                let night = night_of(e.date);
+                if self.shift_night == Some(night) {
+                    self.report(AnomalyKind::OverlappingShift, e);
+                }
+                self.shift_night = Some(night);
+                let sleep = self.close_unterminated_sleep(Some(e.date));
+                self.sleep_state = PartialSleepState::NotSleeping(guard_id);
+                sleep
+            }
+            EventType::WakeUp => {
+                match self.sleep_state {
+                    PartialSleepState::Sleeping(..) => return self.close_sleep(e.date),
+                    PartialSleepState::NotSleeping(_) => self.report(AnomalyKind::WakeUpWithoutSleep, e),
+                    PartialSleepState::NoGuard => self.report(AnomalyKind::EventBeforeShift, e),
                 }
-                _ => return unexpected_state_for_event(e, &sleep_state)
+                None
+            }
+            EventType::FallAsleep => {
+                match self.sleep_state {
+                    PartialSleepState::NotSleeping(guard_id) => self.sleep_state = PartialSleepState::Sleeping(guard_id, e.clone()),
+                    PartialSleepState::Sleeping(..) => self.report(AnomalyKind::FallAsleepWhileSleeping, e),
+                    PartialSleepState::NoGuard => self.report(AnomalyKind::EventBeforeShift, e),
+                }
+                None
+            }
+        }
+    }
+
+    /// Ends the log, a sleep still going on is closed at the start of the next hour.
+    /// Returns that sleep along with the anomalies not taken yet.
+    pub fn finish(mut self) -> (Option<(usize, Sleep)>, Vec<Anomaly>) {
+        let sleep = self.close_unterminated_sleep(None);
+        (sleep, self.anomalies)
+    }
+
+    /// A guard who never woke up is taken to sleep till the start of the next hour, or till
+    /// the next shift begins if that comes first.
+    fn close_unterminated_sleep(&mut self, next_shift: Option<DateTime<FixedOffset>>) -> Option<(usize, Sleep)> {
+        let fell_asleep = if let PartialSleepState::Sleeping(_, fell_asleep) = &self.sleep_state {
+            fell_asleep.clone()
+        } else {
+            return None;
+        };
+        self.report(AnomalyKind::UnterminatedSleep, &fell_asleep);
+        let hour_end = start_of_minute(fell_asleep.date) + Duration::minutes(60 - i64::from(fell_asleep.date.minute()));
+        self.close_sleep(next_shift.map_or(hour_end, |next_shift| next_shift.min(hour_end)))
+    }
+
+    fn close_sleep(&mut self, to: DateTime<FixedOffset>) -> Option<(usize, Sleep)> {
+        if let PartialSleepState::Sleeping(guard_id, fell_asleep) = &self.sleep_state {
+            let (guard_id, from) = (*guard_id, fell_asleep.date);
+            self.sleep_state = PartialSleepState::NotSleeping(guard_id);
+            let sleep = Sleep { from, to };
+            if from < to && !sleep.is_empty() {
+                return Some((guard_id, sleep));
             }
-            EventType::FallAsleep => match sleep_state {
-                PartialSleepState::NotSleeping(guard_id) => sleep_state = PartialSleepState::Sleeping(guard_id, e.date.minute() as usize),
-                _ => return unexpected_state_for_event(e, &sleep_state)
+        }
+        None
+    }
+
+    fn report(&mut self, kind: AnomalyKind, e: &Event) {
+        self.anomalies.push(Anomaly { kind, line: e.line, event: e.to_string() });
+    }
+}
+
+const MINUTES_IN_HOUR: usize = 60;
+
+/// Minutes of the midnight hour of a single night the guard was asleep.
+pub struct TimelineRow {
+    pub night: NaiveDate,
+    pub guard_id: usize,
+    pub asleep: Vec<bool>,
+}
+
+/// One row for every shift in the events, ordered by night and guard id.
+pub fn build_timeline(events: &[Event]) -> Vec<TimelineRow> {
+    let mut rows = BTreeMap::new();
+    for e in events {
+        if let EventType::ShiftBegin(guard_id) = e.e_type {
+            rows.entry((night_of(e.date), guard_id)).or_insert_with(|| vec![false; MINUTES_IN_HOUR]);
+        }
+    }
+    let (sleeps_grouped, _) = build_sleeps_grouped_by_guard_lenient(events);
+    for (guard_id, sleeps) in &sleeps_grouped {
+        for minute in sleeps.iter().flat_map(|s| s.minutes()).filter(|m| m.hour() == 0) {
+            if let Some(asleep) = rows.get_mut(&(minute.naive_local().date(), *guard_id)) {
+                asleep[minute.minute() as usize] = true;
             }
         }
     }
-    Ok(sleeps_grouped)
+    rows.into_iter()
+        .map(|((night, guard_id), asleep)| TimelineRow { night, guard_id, asleep })
+        .collect()
+}
+
+/// Renders the rows as in the puzzle description, `#` marks minutes the guard was asleep.
+pub fn timeline_chart(rows: &[TimelineRow]) -> String {
+    let ids: Vec<String> = rows.iter().map(|r| format!("#{}", r.guard_id)).collect();
+    let id_width = ids.iter().map(|id| id.len()).max().unwrap_or(0).max("ID".len());
+    let tens: String = (0..MINUTES_IN_HOUR).map(|m| format!("{}", m / 10)).collect();
+    let units: String = (0..MINUTES_IN_HOUR).map(|m| format!("{}", m % 10)).collect();
+
+    let mut chart = format!("Date   {:<w$}  Minute\n", "ID", w = id_width);
+    chart.push_str(&format!("       {:<w$}  {}\n", "", tens, w = id_width));
+    chart.push_str(&format!("       {:<w$}  {}\n", "", units, w = id_width));
+    for (row, id) in rows.iter().zip(ids.iter()) {
+        let minutes: String = row.asleep.iter().map(|&a| if a { '#' } else { '.' }).collect();
+        chart.push_str(&format!("{}  {:<w$}  {}\n", row.night.format("%m-%d"), id, minutes, w = id_width));
+    }
+    chart
+}
+
+/// One line per row with the night, the guard id and 1 for every minute the guard was asleep, 0 otherwise.
+pub fn timeline_csv(rows: &[TimelineRow]) -> String {
+    let minutes: Vec<String> = (0..MINUTES_IN_HOUR).map(|m| format!("{:02}", m)).collect();
+    let mut csv = format!("date,guard_id,{}\n", minutes.join(","));
+    for row in rows {
+        let asleep: Vec<&str> = row.asleep.iter().map(|&a| if a { "1" } else { "0" }).collect();
+        csv.push_str(&format!("{},{},{}\n", row.night.format("%Y-%m-%d"), row.guard_id, asleep.join(",")));
+    }
+    csv
+}
+
+/// Date of the midnight hour covered by a shift beginning at the given time.
+fn night_of(shift_begin: DateTime<FixedOffset>) -> NaiveDate {
+    let date = shift_begin.naive_local().date();
+    if shift_begin.hour() >= 12 {
+        date.succ_opt().unwrap()
+    } else {
+        date
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnomalyKind {
+    EventBeforeShift,
+    WakeUpWithoutSleep,
+    FallAsleepWhileSleeping,
+    UnterminatedSleep,
+    DuplicateTimestamp,
+    OverlappingShift,
+}
+
+impl fmt::Display for AnomalyKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            AnomalyKind::EventBeforeShift => "event before any shift began",
+            AnomalyKind::WakeUpWithoutSleep => "woke up without falling asleep",
+            AnomalyKind::FallAsleepWhileSleeping => "fell asleep while already sleeping",
+            AnomalyKind::UnterminatedSleep => "sleep never ended during the shift",
+            AnomalyKind::DuplicateTimestamp => "same timestamp as the previous event",
+            AnomalyKind::OverlappingShift => "shift began during another shift of the same night",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+#[derive(Debug)]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    pub line: usize,
+    pub event: String,
 }
 
-fn unexpected_state_for_event<T>(e: &Event, state: &PartialSleepState) -> Result<T, String> {
-    Err(format!("Unexpected state for event {:?}: {:?}", e, state))
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}: {}", self.line, self.kind, self.event)
+    }
 }
 
 #[derive(Debug)]
-enum PartialSleepState {
+pub enum PartialSleepState {
     NoGuard,
+    /// Guard id
     NotSleeping(usize),
-    Sleeping(usize, usize)
+    /// Guard id and the event of falling asleep
+    Sleeping(usize, Event)
 }
 
-struct Sleep {
-    from: usize,
-    to: usize
+/// Guard was asleep from `from` up to, but not including, `to`. Minutes of the sleep are
+/// those starting within it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sleep {
+    pub from: DateTime<FixedOffset>,
+    pub to: DateTime<FixedOffset>
 }
 
 impl Sleep {
-    fn len(&self) -> usize {
-        self.to - self.from + 1
+    /// Number of minutes starting during the sleep.
+    pub fn len(&self) -> usize {
+        (first_minute_from(self.to) - self.first_minute()).num_minutes() as usize
+    }
+
+    /// True if no minute starts during the sleep.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// A guard falling asleep in the middle of a minute sleeps from the next one.
+    fn first_minute(&self) -> DateTime<FixedOffset> {
+        first_minute_from(self.from)
+    }
+
+    /// Start of every minute of the sleep.
+    fn minutes(&self) -> impl Iterator<Item=DateTime<FixedOffset>> {
+        let first = self.first_minute();
+        (0..self.len()).map(move |i| first + Duration::minutes(i as i64))
+    }
+
+    fn first_minute_of_day(&self) -> usize {
+        let first = self.first_minute();
+        (first.hour() * 60 + first.minute()) as usize
+    }
+
+    /// Minute of the day for every minute of the sleep, repeated if the sleep lasts for days.
+    fn minutes_of_day(&self) -> impl Iterator<Item=usize> {
+        let first = self.first_minute_of_day();
+        (first..(first + self.len())).map(|m| m % MINUTES_IN_DAY)
+    }
+
+    /// Night of the shift the sleep began in.
+    fn night(&self) -> NaiveDate {
+        night_of(self.from)
     }
 }
 
-#[derive(Debug)]
-enum EventType {
+fn start_of_minute(moment: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+    moment - Duration::seconds(i64::from(moment.second())) - Duration::nanoseconds(i64::from(moment.nanosecond()))
+}
+
+/// Start of the first minute beginning at the moment or later.
+fn first_minute_from(moment: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+    let start = start_of_minute(moment);
+    if start == moment { start } else { start + Duration::minutes(1) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventType {
     WakeUp,
     FallAsleep,
     ShiftBegin(usize)
 }
 
-#[derive(Debug)]
-struct Event {
+#[derive(Debug, Clone)]
+pub struct Event {
     e_type: EventType,
-    date: DateTime<Utc>
+    /// Keeps the offset of the log, nights and minutes of the day are those of its clock
+    date: DateTime<FixedOffset>,
+    /// Line of the log file the event was read from, starting with 1
+    line: usize
 }
 
-fn read_events_from_file_sorted(file_name: &str) -> Vec<Event> {
-    let mut events: Vec<Event> = read_lines_from_file(file_name).iter()
-        .map(|l| Event::parse(l.trim()).unwrap_or_else(|e| panic!("Error during event parsing: {}", e)))
-        .collect();
+pub fn read_events_from_file_sorted(file_name: &str) -> Vec<Event> {
+    read_events_from_file_sorted_with_format(file_name, &TimestampFormat::default())
+}
+
+pub fn read_events_from_file_sorted_with_format(file_name: &str, format: &TimestampFormat) -> Vec<Event> {
+    let mut events = read_events_from_file(file_name, format)
+        .unwrap_or_else(|e| panic!("Error during event parsing: {}", e));
 
-    events.sort_unstable_by_key(|e| e.date);
+    events.sort_by_key(|e| e.date);
 
     events
 }
 
+/// Events in the order of the file, empty lines are skipped. Errors name the file and the line.
+fn read_events_from_file(file_name: &str, format: &TimestampFormat) -> Result<Vec<Event>, String> {
+    let content = fs::read_to_string(file_name).map_err(|e| format!("{}: {}", file_name, e))?;
+    content.lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| Event::parse_with_format(l.trim(), i + 1, format)
+            .map_err(|e| format!("{}:{}: {}", file_name, i + 1, e)))
+        .collect()
+}
+
+/// Events merged from several logs, sorted by timestamp.
+pub struct MergedLog {
+    pub events: Vec<Event>,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+/// Different events sharing a timestamp, all of them are kept in the merged log.
+#[derive(Debug, PartialEq)]
+pub struct MergeConflict {
+    pub date: DateTime<FixedOffset>,
+    /// `file:line: event` for every distinct event
+    pub sources: Vec<String>,
+}
+
+/// Merges all files of the directory, in the order of their names.
+pub fn merge_event_log_dir(dir_name: &str, format: &TimestampFormat) -> Result<MergedLog, String> {
+    let mut file_names = Vec::new();
+    let entries = fs::read_dir(dir_name).map_err(|e| format!("{}: {}", dir_name, e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| format!("{}: {}", dir_name, e))?.path();
+        if path.is_file() {
+            file_names.push(path.to_string_lossy().into_owned());
+        }
+    }
+    file_names.sort();
+    merge_event_logs(&file_names, format)
+}
+
+/// Fails on the first file which can't be read or has an unparsable line.
+pub fn merge_event_logs(file_names: &[String], format: &TimestampFormat) -> Result<MergedLog, String> {
+    let logs = file_names.iter()
+        .map(|f| read_events_from_file(f, format).map(|events| (f.clone(), events)))
+        .collect::<Result<_, _>>()?;
+    Ok(merge_events(logs))
+}
+
+/// Identical events are kept once. Events with equal timestamps stay in the order of the logs
+/// and, within a log, in the order of lines.
+fn merge_events(logs: Vec<(String, Vec<Event>)>) -> MergedLog {
+    let mut all_events: Vec<(&str, Event)> = logs.iter()
+        .flat_map(|(file_name, events)| events.iter().map(move |e| (file_name.as_str(), e.clone())))
+        .collect();
+    all_events.sort_by_key(|(_, e)| e.date);
+
+    let mut events: Vec<Event> = Vec::with_capacity(all_events.len());
+    let mut conflicts = Vec::new();
+    let mut group_start = 0;
+    while group_start < all_events.len() {
+        let date = all_events[group_start].1.date;
+        let group_len = all_events[group_start..].iter().take_while(|(_, e)| e.date == date).count();
+        let mut distinct: Vec<&(&str, Event)> = Vec::new();
+        for entry in &all_events[group_start..(group_start + group_len)] {
+            if !distinct.iter().any(|(_, d)| d.e_type == entry.1.e_type) {
+                distinct.push(entry);
+            }
+        }
+        if distinct.len() > 1 {
+            let sources = distinct.iter().map(|(file_name, e)| format!("{}:{}: {}", file_name, e.line, e)).collect();
+            conflicts.push(MergeConflict { date, sources });
+        }
+        events.extend(distinct.into_iter().map(|(_, e)| e.clone()));
+        group_start += group_len;
+    }
+
+    MergedLog { events, conflicts }
+}
+
+/// How timestamps between the brackets of the log lines are written.
+#[derive(Debug, Clone)]
+pub struct TimestampFormat {
+    /// `chrono` format, if it has an offset (`%z`, `%:z` or `%#z`) the offset of each timestamp is used
+    pub pattern: String,
+    /// Offset of timestamps without one
+    pub timezone: FixedOffset,
+}
+
+impl Default for TimestampFormat {
+    /// Puzzle format, `1518-11-01 00:05` in UTC.
+    fn default() -> TimestampFormat {
+        TimestampFormat::new("%Y-%m-%d %H:%M", FixedOffset::east_opt(0).unwrap())
+    }
+}
+
+impl TimestampFormat {
+    pub fn new(pattern: &str, timezone: FixedOffset) -> TimestampFormat {
+        TimestampFormat { pattern: pattern.to_string(), timezone }
+    }
+
+    /// `1518-11-01 00:05:30` in the given timezone.
+    pub fn with_seconds(timezone: FixedOffset) -> TimestampFormat {
+        TimestampFormat::new("%Y-%m-%d %H:%M:%S", timezone)
+    }
+
+    /// `1518-11-01T00:05:30+03:00`.
+    pub fn iso_8601() -> TimestampFormat {
+        TimestampFormat::new("%Y-%m-%dT%H:%M:%S%:z", FixedOffset::east_opt(0).unwrap())
+    }
+
+    fn has_offset(&self) -> bool {
+        ["%z", "%:z", "%#z"].iter().any(|o| self.pattern.contains(o))
+    }
+
+    fn parse(&self, str: &str) -> Result<DateTime<FixedOffset>, String> {
+        if self.has_offset() {
+            DateTime::parse_from_str(str, &self.pattern).map_err(|e| e.to_string())
+        } else {
+            let naive = NaiveDateTime::parse_from_str(str, &self.pattern).map_err(|e| e.to_string())?;
+            self.timezone.from_local_datetime(&naive)
+                .single()
+                .ok_or_else(|| format!("Ambiguous local time {}", naive))
+        }
+    }
+}
+
 impl Event {
-    fn parse(str: &str) -> Result<Event, String>  {
-        let (date_part, e_type_part) = scan_fmt!(str, "[{/[0-9-: ]+/}] {/[A-Za-z#0-9 ]+/}", String, String);
+    pub fn event_type(&self) -> EventType {
+        self.e_type
+    }
+
+    pub fn date(&self) -> DateTime<FixedOffset> {
+        self.date
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn parse(str: &str, line: usize) -> Result<Event, String> {
+        Event::parse_with_format(str, line, &TimestampFormat::default())
+    }
+
+    pub fn parse_with_format(str: &str, line: usize, format: &TimestampFormat) -> Result<Event, String> {
+        let (date_part, e_type_part) = match str.find(']') {
+            Some(closing) if str.starts_with('[') => (Some(&str[1..closing]), Some(str[(closing + 1)..].trim())),
+            _ => (None, None)
+        };
 
         let date = if let Some(date_part) = date_part {
-            match Utc.datetime_from_str(&date_part, "%Y-%m-%d %H:%M") {
+            match format.parse(date_part) {
                 Ok(d) => d,
                 Err(e) => return Err(format!("Couldn't parse date, error: {} in event {}", e, str))
             }
@@ -144,15 +776,15 @@ impl Event {
         };
 
         let e_type = if let Some(e_type_part) = e_type_part {
-            match e_type_part.as_ref() {
+            match e_type_part {
                 "wakes up" => EventType::WakeUp,
                 "falls asleep" => EventType::FallAsleep,
                 _ => {
-                    let guard_id = scan_fmt!(&e_type_part, "Guard #{d} begins shift", usize);
+                    let guard_id = scan_fmt!(e_type_part, "Guard #{d} begins shift", usize);
                     if let Some(guard_id) = guard_id {
                         EventType::ShiftBegin(guard_id)
                     } else {
-                        return Err(format!("Unable to parse e_type: {}", &e_type_part));
+                        return Err(format!("Unable to parse e_type: {}", e_type_part));
                     }
                 }
             }
@@ -160,6 +792,421 @@ impl Event {
             return Err(format!("Couldn't find e_type part in event {}", str));
         };
 
-        Ok(Event { e_type, date })
+        Ok(Event { e_type, date, line })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] ", self.date.format("%Y-%m-%d %H:%M"))?;
+        match self.e_type {
+            EventType::WakeUp => write!(f, "wakes up"),
+            EventType::FallAsleep => write!(f, "falls asleep"),
+            EventType::ShiftBegin(guard_id) => write!(f, "Guard #{} begins shift", guard_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn utc() -> FixedOffset {
+        FixedOffset::east_opt(0).unwrap()
+    }
+
+    fn day(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn timestamp(offset: FixedOffset, date: NaiveDate, hour: u32, minute: u32, second: u32) -> DateTime<FixedOffset> {
+        offset.from_local_datetime(&date.and_hms_opt(hour, minute, second).unwrap()).single().unwrap()
+    }
+
+    const TEST_INPUT: &str = r#"
+        [1518-11-01 00:00] Guard #10 begins shift
+        [1518-11-01 00:05] falls asleep
+        [1518-11-01 00:25] wakes up
+        [1518-11-01 00:30] falls asleep
+        [1518-11-01 00:55] wakes up
+        [1518-11-01 23:58] Guard #99 begins shift
+        [1518-11-02 00:40] falls asleep
+        [1518-11-02 00:50] wakes up
+        [1518-11-03 00:05] Guard #10 begins shift
+        [1518-11-03 00:24] falls asleep
+        [1518-11-03 00:29] wakes up
+        [1518-11-04 00:02] Guard #99 begins shift
+        [1518-11-04 00:36] falls asleep
+        [1518-11-04 00:46] wakes up
+        [1518-11-05 00:03] Guard #99 begins shift
+        [1518-11-05 00:45] falls asleep
+        [1518-11-05 00:55] wakes up
+        "#;
+
+    fn parse_events(input: &str) -> Vec<Event> {
+        parse_events_with_format(input, &TimestampFormat::default())
+    }
+
+    fn parse_events_with_format(input: &str, format: &TimestampFormat) -> Vec<Event> {
+        input
+            .split('\n')
+            .map(|l| l.trim())
+            .enumerate()
+            .filter(|(_, l)| !l.is_empty())
+            .map(|(i, l)| Event::parse_with_format(l, i, format).unwrap())
+            .collect()
+    }
+
+    fn parse_events_sorted(input: &str) -> Vec<Event> {
+        parse_events_sorted_with_format(input, &TimestampFormat::default())
+    }
+
+    fn parse_events_sorted_with_format(input: &str, format: &TimestampFormat) -> Vec<Event> {
+        let mut events = parse_events_with_format(input, format);
+        events.sort_by_key(|e| e.date);
+        events
+    }
+
+    #[test]
+    fn test_guard_log_processor() {
+        let events = parse_events_sorted(r#"
+            [1518-11-01 00:00] Guard #10 begins shift
+            [1518-11-01 00:05] falls asleep
+            [1518-11-01 00:25] wakes up
+            [1518-11-01 00:25] wakes up
+            [1518-11-01 00:30] falls asleep
+            [1518-11-01 23:58] Guard #99 begins shift
+            [1518-11-02 00:40] falls asleep
+            "#);
+        let mut processor = GuardLogProcessor::new();
+        let at = |h, m| timestamp(utc(), day(1518, 11, 1), h, m, 0);
+
+        assert_eq!(None, processor.process(&events[0]));
+        assert!(matches!(processor.state(), PartialSleepState::NotSleeping(10)));
+        assert_eq!(None, processor.process(&events[1]));
+        assert!(matches!(processor.state(), PartialSleepState::Sleeping(10, e) if e.date() == at(0, 5) && e.line() == 2));
+        assert_eq!(Some((10, Sleep { from: at(0, 5), to: at(0, 25) })), processor.process(&events[2]));
+        assert!(processor.take_anomalies().is_empty());
+
+        assert_eq!(None, processor.process(&events[3]));
+        assert_eq!(None, processor.process(&events[4]));
+        let anomalies: Vec<_> = processor.take_anomalies().iter().map(|a| a.kind).collect();
+        assert_eq!(vec![AnomalyKind::DuplicateTimestamp], anomalies);
+
+        assert_eq!(Some((10, Sleep { from: at(0, 30), to: at(1, 0) })), processor.process(&events[5]));
+        assert!(matches!(processor.state(), PartialSleepState::NotSleeping(99)));
+        assert_eq!(None, processor.process(&events[6]));
+
+        let (last_sleep, anomalies) = processor.finish();
+        let from = timestamp(utc(), day(1518, 11, 2), 0, 40, 0);
+        assert_eq!(Some((99, Sleep { from, to: from + Duration::minutes(20) })), last_sleep);
+        let anomalies: Vec<_> = anomalies.iter().map(|a| (a.line, a.kind)).collect();
+        assert_eq!(vec![(5, AnomalyKind::UnterminatedSleep), (7, AnomalyKind::UnterminatedSleep)], anomalies);
+
+        let events = parse_events_sorted(r#"
+            [1518-11-01 00:00] Guard #10 begins shift
+            [1518-11-01 00:30] falls asleep
+            [1518-11-01 00:45] Guard #99 begins shift
+            "#);
+        let mut processor = GuardLogProcessor::new();
+        processor.process(&events[0]);
+        processor.process(&events[1]);
+        assert_eq!(Some((10, Sleep { from: at(0, 30), to: at(0, 45) })), processor.process(&events[2]));
+    }
+
+    #[test]
+    fn test_timestamp_formats() {
+        let date = |str: &str, format: &TimestampFormat| Event::parse_with_format(str, 1, format).map(|e| e.date);
+        let plus_one = FixedOffset::east_opt(3600).unwrap();
+        let plus_three = FixedOffset::east_opt(3 * 3600).unwrap();
+
+        assert_eq!(Ok(timestamp(utc(), day(1518, 11, 1), 0, 5, 0)), date("[1518-11-01 00:05] falls asleep", &TimestampFormat::default()));
+        assert_eq!(
+            Ok(timestamp(utc(), day(1518, 11, 1), 0, 5, 30)),
+            date("[1518-11-01 00:05:30] falls asleep", &TimestampFormat::with_seconds(utc()))
+        );
+        let local = date("[1518-11-01 00:05:30] falls asleep", &TimestampFormat::with_seconds(plus_one)).unwrap();
+        assert_eq!(timestamp(plus_one, day(1518, 11, 1), 0, 5, 30), local);
+        assert_eq!(timestamp(utc(), day(1518, 10, 31), 23, 5, 30), local);
+        assert_eq!((plus_one, 0), (*local.offset(), local.hour()));
+        let local = date("[1518-11-01T00:05:00+03:00] wakes up", &TimestampFormat::iso_8601()).unwrap();
+        assert_eq!(timestamp(utc(), day(1518, 10, 31), 21, 5, 0), local);
+        assert_eq!((plus_three, 0), (*local.offset(), local.hour()));
+        assert_eq!(
+            Ok(timestamp(utc(), day(1518, 11, 1), 0, 5, 0)),
+            date("[1518-11-01T00:05:00+00:00] Guard #10 begins shift", &TimestampFormat::iso_8601())
+        );
+        assert_eq!(
+            Ok(timestamp(plus_one, day(1518, 11, 1), 0, 5, 0)),
+            date("[1518-11-01T00:05] wakes up", &TimestampFormat::new("%Y-%m-%dT%H:%M", plus_one))
+        );
+
+        assert!(date("[1518-11-01 00:05] falls asleep", &TimestampFormat::iso_8601()).is_err());
+        assert!(date("[1518-11-01T00:05:00+03:00] falls asleep", &TimestampFormat::default()).is_err());
+        assert!(date("1518-11-01 00:05 falls asleep", &TimestampFormat::default()).is_err());
+        assert!(date("[1518-11-01 00:05] dances", &TimestampFormat::default()).is_err());
+    }
+
+    #[test]
+    fn test_merge_events() {
+        let first = parse_events(r#"
+            [1518-11-01 00:25] wakes up
+            [1518-11-01 00:00] Guard #10 begins shift
+            [1518-11-01 00:05] falls asleep
+            "#);
+        let second = parse_events(r#"
+            [1518-11-01 00:05] falls asleep
+            [1518-11-01 00:00] Guard #10 begins shift
+            [1518-11-01 00:25] falls asleep
+            [1518-11-01 00:30] wakes up
+            "#);
+        let merged = merge_events(vec![("first".to_string(), first), ("second".to_string(), second)]);
+
+        let merged_events: Vec<_> = merged.events.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "[1518-11-01 00:00] Guard #10 begins shift",
+                "[1518-11-01 00:05] falls asleep",
+                "[1518-11-01 00:25] wakes up",
+                "[1518-11-01 00:25] falls asleep",
+                "[1518-11-01 00:30] wakes up",
+            ],
+            merged_events
+        );
+        assert_eq!(
+            vec![MergeConflict {
+                date: timestamp(utc(), day(1518, 11, 1), 0, 25, 0),
+                sources: vec![
+                    "first:1: [1518-11-01 00:25] wakes up".to_string(),
+                    "second:3: [1518-11-01 00:25] falls asleep".to_string(),
+                ],
+            }],
+            merged.conflicts
+        );
+
+        let dir = env::temp_dir().join(format!("raoc-ch4-merge-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let good = dir.join("good.log").to_string_lossy().into_owned();
+        let bad = dir.join("bad.log").to_string_lossy().into_owned();
+        let missing = dir.join("missing.log").to_string_lossy().into_owned();
+        fs::write(&good, "[1518-11-01 00:00] Guard #10 begins shift\n").unwrap();
+        fs::write(&bad, "[1518-11-01 00:05] falls asleep\n\n[1518-11-01 00:25] snores\n").unwrap();
+        let format = TimestampFormat::default();
+
+        assert_eq!(1, merge_event_logs(std::slice::from_ref(&good), &format).unwrap().events.len());
+        let error = merge_event_logs(&[good.clone(), bad.clone()], &format).err().unwrap();
+        assert!(error.starts_with(&format!("{}:3: ", bad)), "{}", error);
+        let error = merge_event_logs(&[missing.clone(), good], &format).err().unwrap();
+        assert!(error.starts_with(&format!("{}: ", missing)), "{}", error);
+        assert!(merge_event_log_dir(&dir.to_string_lossy(), &format).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_strategies() {
+        let sleeps_grouped = build_sleeps_grouped_by_guard(&parse_events_sorted(TEST_INPUT)).unwrap();
+        let select = |name| strategy_by_name(name).unwrap().select(&sleeps_grouped).unwrap();
+
+        let result = select("most-slept-guard");
+        assert_eq!(StrategyResult { guard_id: 10, minute: 24, score: 50 }, result);
+        assert_eq!(240, result.answer());
+        assert_eq!(StrategyResult { guard_id: 99, minute: 45, score: 3 }, select("most-slept-minute"));
+        assert_eq!(StrategyResult { guard_id: 99, minute: 45, score: 3 }, select("most-nights-asleep"));
+        assert_eq!(StrategyResult { guard_id: 10, minute: 30, score: 25 }, select("longest-nap"));
+        assert_eq!(StrategyResult { guard_id: 99, minute: 45, score: 3 }, select("most-consistent-minute"));
+
+        assert!(strategy_by_name("unknown").is_none());
+        for name in STRATEGY_NAMES.iter() {
+            assert!(strategy_by_name(name).unwrap().select(&HashMap::new()).is_none());
+        }
+    }
+
+    #[test]
+    fn test_guard_stats() {
+        let events = parse_events_sorted(TEST_INPUT);
+        let stats = build_guard_stats(&build_sleeps_grouped_by_guard(&events).unwrap(), &shift_nights_by_guard(&events));
+        assert_eq!(vec![10, 99], stats.iter().map(|s| s.guard_id).collect::<Vec<_>>());
+
+        let guard_10 = &stats[0];
+        assert_eq!(50, guard_10.total_minutes_asleep);
+        assert_eq!(2, guard_10.shifts);
+        assert_eq!(25, guard_10.longest_nap);
+        assert_eq!(50.0 / 3.0, guard_10.average_nap);
+        assert_eq!(day(1518, 11, 1), guard_10.first_seen);
+        assert_eq!(day(1518, 11, 3), guard_10.last_seen);
+        assert_eq!(2, guard_10.minutes_histogram[24]);
+        assert_eq!(24, guard_10.most_slept_minute());
+
+        assert_eq!(
+            "guard_id,total_minutes_asleep,shifts,longest_nap,average_nap,first_seen,last_seen,most_slept_minute\n\
+             10,50,2,25,16.67,1518-11-01,1518-11-03,24\n\
+             99,30,3,10,10.00,1518-11-02,1518-11-05,45\n",
+            guard_stats_csv(&stats)
+        );
+        let histogram_csv = minutes_histogram_csv(&stats);
+        assert_eq!(1 + 49 + 19, histogram_csv.lines().count());
+        assert!(histogram_csv.contains("\n10,24,2\n"));
+        assert!(histogram_csv.contains("\n99,45,3\n"));
+
+        let events = parse_events_sorted(r#"
+            [1518-10-30 23:58] Guard #10 begins shift
+            [1518-11-01 00:00] Guard #10 begins shift
+            [1518-11-01 00:05] falls asleep
+            [1518-11-01 00:25] wakes up
+            [1518-11-02 00:00] Guard #99 begins shift
+            [1518-11-04 00:00] Guard #10 begins shift
+            "#);
+        let stats = build_guard_stats(&build_sleeps_grouped_by_guard(&events).unwrap(), &shift_nights_by_guard(&events));
+        assert_eq!(
+            "guard_id,total_minutes_asleep,shifts,longest_nap,average_nap,first_seen,last_seen,most_slept_minute\n\
+             10,20,3,20,20.00,1518-10-31,1518-11-04,5\n\
+             99,0,1,0,0.00,1518-11-02,1518-11-02,0\n",
+            guard_stats_csv(&stats)
+        );
+    }
+
+    #[test]
+    fn test_most_consistent_minute() {
+        let events = parse_events_sorted(r#"
+            [1518-11-01 00:00] Guard #10 begins shift
+            [1518-11-01 00:01] falls asleep
+            [1518-11-03 00:00] wakes up
+            [1518-11-04 00:00] Guard #99 begins shift
+            [1518-11-04 00:05] falls asleep
+            [1518-11-04 00:06] wakes up
+            [1518-11-05 00:00] Guard #99 begins shift
+            [1518-11-05 00:05] falls asleep
+            [1518-11-05 00:07] wakes up
+            "#);
+        let sleeps_grouped = build_sleeps_grouped_by_guard(&events).unwrap();
+        assert_eq!(Some(StrategyResult { guard_id: 10, minute: 1, score: 2 }), MostSleptMinute.select(&sleeps_grouped));
+        assert_eq!(Some(StrategyResult { guard_id: 99, minute: 5, score: 2 }), MostConsistentMinute.select(&sleeps_grouped));
+    }
+
+    #[test]
+    fn test_sleeps_across_midnight() {
+        let events = parse_events_sorted(r#"
+            [1518-11-01 23:50] Guard #10 begins shift
+            [1518-11-01 23:57] falls asleep
+            [1518-11-02 00:00] wakes up
+            [1518-11-02 00:10] falls asleep
+            [1518-11-02 02:00] wakes up
+            [1518-11-02 23:58] Guard #10 begins shift
+            [1518-11-02 23:59] falls asleep
+            [1518-11-03 00:02] wakes up
+            "#);
+        let sleeps_grouped = build_sleeps_grouped_by_guard(&events).unwrap();
+        let sleeps = &sleeps_grouped[&10];
+        assert_eq!(vec![3, 110, 3], sleeps.iter().map(|s| s.len()).collect::<Vec<_>>());
+        assert_eq!((23 * 60 + 59, 2), find_most_slept_minute(sleeps));
+
+        let events = parse_events_sorted_with_format(r#"
+            [1518-11-01 23:50:00] Guard #10 begins shift
+            [1518-11-02 00:05:30] falls asleep
+            [1518-11-02 00:05:50] wakes up
+            [1518-11-02 00:10:50] falls asleep
+            [1518-11-02 00:12:10] wakes up
+            [1518-11-02 00:20:00] falls asleep
+            [1518-11-02 00:25:30] wakes up
+            [1518-11-02 00:50:20] falls asleep
+            "#, &TimestampFormat::with_seconds(utc()));
+        let (sleeps_grouped, _) = build_sleeps_grouped_by_guard_lenient(&events);
+        let sleeps = &sleeps_grouped[&10];
+        assert_eq!(vec![2, 6, 9], sleeps.iter().map(|s| s.len()).collect::<Vec<_>>());
+        let stats = GuardStats::new(10, sleeps, &[sleeps[0].night()]).unwrap();
+        assert_eq!(17.0 / 3.0, stats.average_nap);
+        let slept: Vec<_> = (0..60).filter(|&m| stats.minutes_histogram[m] > 0).collect();
+        assert_eq!(vec![11, 12, 20, 21, 22, 23, 24, 25, 51, 52, 53, 54, 55, 56, 57, 58, 59], slept);
+    }
+
+    #[test]
+    fn test_anomalies() {
+        let events = parse_events_sorted(r#"
+            [1518-11-01 00:01] falls asleep
+            [1518-11-01 00:05] Guard #10 begins shift
+            [1518-11-01 00:06] wakes up
+            [1518-11-01 00:10] falls asleep
+            [1518-11-01 00:12] falls asleep
+            [1518-11-01 00:20] wakes up
+            [1518-11-01 00:30] falls asleep
+            [1518-11-01 23:58] Guard #99 begins shift
+            [1518-11-02 00:02] Guard #10 begins shift
+            [1518-11-02 00:40] falls asleep
+            [1518-11-02 00:40] falls asleep
+            [1518-11-02 00:45] wakes up
+            [1518-11-03 00:00] Guard #99 begins shift
+            [1518-11-03 00:50] falls asleep
+            "#);
+
+        let anomalies: Vec<_> = validate_events(&events).iter().map(|a| (a.line, a.kind)).collect();
+        assert_eq!(
+            vec![
+                (1, AnomalyKind::EventBeforeShift),
+                (3, AnomalyKind::WakeUpWithoutSleep),
+                (5, AnomalyKind::FallAsleepWhileSleeping),
+                (7, AnomalyKind::UnterminatedSleep),
+                (9, AnomalyKind::OverlappingShift),
+                (11, AnomalyKind::DuplicateTimestamp),
+                (14, AnomalyKind::UnterminatedSleep),
+            ],
+            anomalies
+        );
+
+        let error = build_sleeps_grouped_by_guard(&events).map(|_| ()).unwrap_err();
+        assert!(error.starts_with("7 anomalies in guard log:\nline 1: event before any shift began: [1518-11-01 00:01] falls asleep"));
+
+        let (sleeps_grouped, _) = build_sleeps_grouped_by_guard_lenient(&events);
+        let lengths = |guard_id| sleeps_grouped[&guard_id].iter().map(|s| s.len()).collect::<Vec<_>>();
+        assert_eq!(vec![10, 30, 5], lengths(10));
+        assert_eq!(vec![10], lengths(99));
+    }
+
+    #[test]
+    fn test_timeline() {
+        let rows = build_timeline(&parse_events_sorted(TEST_INPUT));
+        let expected_chart = "\
+Date   ID   Minute
+            000000000011111111112222222222333333333344444444445555555555
+            012345678901234567890123456789012345678901234567890123456789
+11-01  #10  .....####################.....#########################.....
+11-02  #99  ........................................##########..........
+11-03  #10  ........................#####...............................
+11-04  #99  ....................................##########..............
+11-05  #99  .............................................##########.....
+";
+        assert_eq!(expected_chart, timeline_chart(&rows));
+
+        let csv = timeline_csv(&rows);
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(6, lines.len());
+        assert!(lines[0].starts_with("date,guard_id,00,01,"));
+        assert!(lines[0].ends_with(",58,59"));
+        let asleep: Vec<_> = (0..60).map(|m| if (24..29).contains(&m) { "1" } else { "0" }).collect();
+        assert_eq!(format!("1518-11-03,10,{}", asleep.join(",")), lines[3]);
+    }
+
+    #[test]
+    fn test_non_utc_offset() {
+        let format = TimestampFormat::new("%Y-%m-%d %H:%M", FixedOffset::east_opt(3600).unwrap());
+        let local_events = parse_events_sorted_with_format(TEST_INPUT, &format);
+        let utc_events = parse_events_sorted(TEST_INPUT);
+        assert_eq!("[1518-11-01 00:05] falls asleep", local_events[1].to_string());
+
+        let local_sleeps = build_sleeps_grouped_by_guard(&local_events).unwrap();
+        let utc_sleeps = build_sleeps_grouped_by_guard(&utc_events).unwrap();
+        for name in STRATEGY_NAMES.iter() {
+            let strategy = strategy_by_name(name).unwrap();
+            assert_eq!(strategy.select(&utc_sleeps), strategy.select(&local_sleeps), "{}", name);
+        }
+
+        let local_rows = build_timeline(&local_events);
+        let utc_rows = build_timeline(&utc_events);
+        assert_eq!(timeline_chart(&utc_rows), timeline_chart(&local_rows));
+        assert_eq!(timeline_csv(&utc_rows), timeline_csv(&local_rows));
+        assert_eq!(
+            guard_stats_csv(&build_guard_stats(&utc_sleeps, &shift_nights_by_guard(&utc_events))),
+            guard_stats_csv(&build_guard_stats(&local_sleeps, &shift_nights_by_guard(&local_events)))
+        );
     }
 }
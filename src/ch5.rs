@@ -1,4 +1,3 @@
-use std::mem;
 use std::collections::HashSet;
 
 use rayon::prelude::*;
@@ -7,77 +6,412 @@ use crate::common::read_file;
 
 pub fn ch5() {
     let polymers = read_file("ch5.txt");
-    polymers.trim();
+    let polymers = polymers.trim().as_bytes();
 
-    test_polymers_removal(&polymers);
+    test_polymers_removal(polymers);
     react_on_string(polymers);
 }
 
-fn test_polymers_removal(polymers: &str) {
-    println!("min length after some removal: {}", find_min_length_after_removing_polymer(polymers));
+fn test_polymers_removal(polymers: &[u8]) {
+    let lengths_after = lengths_after_removing_each_unit(polymers, &OppositePolarity);
+    for (unit, length_after) in &lengths_after {
+        println!("length after removing {}: {}", *unit as char, length_after);
+    }
+    println!("min length after some removal: {}", min_length(&lengths_after));
+}
+
+fn min_length(lengths_after: &[(u8, usize)]) -> usize {
+    lengths_after.iter()
+        .map(|&(_, length_after)| length_after)
+        .min()
+        .unwrap_or(0)
+}
+
+/// Decides which adjacent units react.
+pub trait ReactionRules: Sync {
+    /// Whether `left` reacts with `right` immediately following it.
+    fn reacts(&self, left: u8, right: u8) -> bool;
+
+    /// Units sharing a type are removed together when searching for the best removal.
+    fn unit_type(&self, unit: u8) -> u8 {
+        unit
+    }
+
+    /// True if removing a unit type from a reduced polymer and reducing it again gives
+    /// the same result as removing it from the original polymer.
+    fn removal_commutes_with_reactions(&self) -> bool {
+        false
+    }
+
+    /// True if every order of reactions leads to the same reduced polymer,
+    /// which lets parts of the polymer be reduced independently.
+    fn is_confluent(&self) -> bool {
+        false
+    }
+}
+
+/// Puzzle rules: the same ASCII letter in opposite cases reacts, the letter is the unit type.
+pub struct OppositePolarity;
+
+impl ReactionRules for OppositePolarity {
+    fn reacts(&self, left: u8, right: u8) -> bool {
+        left != right && left.eq_ignore_ascii_case(&right)
+    }
+
+    fn unit_type(&self, unit: u8) -> u8 {
+        unit.to_ascii_lowercase()
+    }
+
+    /// Reacting units are of the same type, so a removal either takes both of them or none,
+    /// and whatever was between them has reacted completely regardless of the removal.
+    fn removal_commutes_with_reactions(&self) -> bool {
+        true
+    }
+
+    /// A unit reacts only with its own opposite, so of two overlapping reactions `aAa`
+    /// either one leaves the same `a`.
+    fn is_confluent(&self) -> bool {
+        true
+    }
+}
+
+/// Explicit table of reacting pairs, each unit is a type of its own.
+pub struct ReactionTable {
+    reacting: Vec<bool>,
+}
+
+impl ReactionTable {
+    /// `(left, right)` reacts only in this order.
+    pub fn new(pairs: &[(u8, u8)]) -> ReactionTable {
+        let mut reacting = vec![false; 256 * 256];
+        for &(left, right) in pairs {
+            reacting[ReactionTable::idx(left, right)] = true;
+        }
+        ReactionTable { reacting }
+    }
+
+    /// `(left, right)` reacts in both orders.
+    pub fn symmetric(pairs: &[(u8, u8)]) -> ReactionTable {
+        let both_orders: Vec<(u8, u8)> = pairs.iter().flat_map(|&(l, r)| vec![(l, r), (r, l)]).collect();
+        ReactionTable::new(&both_orders)
+    }
+
+    fn idx(left: u8, right: u8) -> usize {
+        usize::from(left) * 256 + usize::from(right)
+    }
 }
 
-fn find_min_length_after_removing_polymer(polymers: &str) -> usize {
-    let polymers: Vec<char> = polymers.chars().collect();
-    unique_chars(&polymers).par_iter()
-        .map(|p| (p, react(remove_polymers(&polymers, *p))))
-        .min_by_key(|&(_, length_after)| length_after)
-        .map(&|(_, length_after)| length_after)
-        .unwrap()
+impl ReactionRules for ReactionTable {
+    fn reacts(&self, left: u8, right: u8) -> bool {
+        self.reacting[ReactionTable::idx(left, right)]
+    }
+}
+
+/// Reactions decided by a function of the left and the right unit, each unit is a type of its own.
+pub struct ReactionPredicate<F>(pub F);
+
+impl<F> ReactionRules for ReactionPredicate<F>
+    where F: Fn(u8, u8) -> bool + Sync
+{
+    fn reacts(&self, left: u8, right: u8) -> bool {
+        (self.0)(left, right)
+    }
+}
+
+/// Length of the fully reacted polymer after removing each unit type, ordered by unit type.
+/// Starts from the already reacted polymer if the rules allow it.
+pub fn lengths_after_removing_each_unit<R: ReactionRules>(polymers: &[u8], rules: &R) -> Vec<(u8, usize)> {
+    let reduced;
+    let start = if rules.removal_commutes_with_reactions() {
+        reduced = reduce(polymers, rules);
+        &reduced
+    } else {
+        polymers
+    };
+    let mut unit_types: Vec<u8> = unique_unit_types(polymers, rules).into_iter().collect();
+    unit_types.sort_unstable();
+    unit_types.par_iter()
+        .map(|&unit_type| (unit_type, react(&remove_polymers(start, unit_type, rules), rules)))
+        .collect()
 }
 
-fn remove_polymers(initial: &Vec<char>, to_remove: char) -> Vec<char> {
+fn remove_polymers<R: ReactionRules>(initial: &[u8], to_remove: u8, rules: &R) -> Vec<u8> {
     initial.iter()
-        .filter(|c| c.to_ascii_lowercase() != to_remove)
-        .map(|c| *c)
+        .filter(|&&c| rules.unit_type(c) != to_remove)
+        .copied()
         .collect()
 }
 
-fn react_on_string(polymers: String) {
-    let length_after = react(str_to_char_vec(polymers));
+fn react_on_string(polymers: &[u8]) {
+    let length_after = reduce_parallel(polymers, &OppositePolarity).len();
     println!("after reactions, length: {}", length_after);
 }
 
-fn react(polymers: Vec<char>) -> usize {
-    let mut before: Vec<char> = polymers;
-    let mut after: Vec<char> = Vec::with_capacity(before.len());
-    let mut reacted = true;
-    while reacted {
-        after.clear();
-        reacted = false;
-        let mut bi = 0;
-        while bi < before.len() - 1 {
-            if !should_react(before[bi], before[bi + 1]) {
-                after.push(before[bi]);
-                bi += 1;
-            } else {
-                bi += 2;
-                reacted = true;
+pub fn react<R: ReactionRules>(polymers: &[u8], rules: &R) -> usize {
+    reduce(polymers, rules).len()
+}
+
+/// Reduces the polymer in a single pass: every unit either reacts with the last unit
+/// left so far, or is left itself. If the rules allow several outcomes, the leftmost
+/// reaction always happens first.
+pub fn reduce<R: ReactionRules>(polymers: &[u8], rules: &R) -> Vec<u8> {
+    let mut left: Vec<u8> = Vec::with_capacity(polymers.len());
+    for &unit in polymers {
+        match left.last() {
+            Some(&last) if rules.reacts(last, unit) => {
+                left.pop();
             }
+            _ => left.push(unit),
         }
-        if bi == before.len() - 1 {
-            after.push(before[bi]);
-        }
-        if after.is_empty() {
-            return 0;
-        }
-        after = mem::replace(&mut before, after);
     }
-    before.len()
+    left
 }
 
-fn should_react(c1: char, c2: char) -> bool {
-    let different_case = (c1.is_ascii_lowercase() && c2.is_ascii_uppercase()) || (c1.is_ascii_uppercase() && c2.is_ascii_lowercase());
-    let same_letter = c1.to_ascii_lowercase() == c2.to_ascii_lowercase();
-    different_case && same_letter
+/// Polymers shorter than this are not worth splitting between threads
+const MIN_PARALLEL_CHUNK: usize = 1 << 16;
+/// Chunks per rayon thread, more chunks even out the load if some of them take longer
+const CHUNKS_PER_THREAD: usize = 4;
+
+/// Reduces chunks of the polymer in parallel and merges them, giving the same result as `reduce`.
+/// Rules which are not confluent are always reduced sequentially.
+pub fn reduce_parallel<R: ReactionRules>(polymers: &[u8], rules: &R) -> Vec<u8> {
+    let chunk_size = (polymers.len() / (rayon::current_num_threads() * CHUNKS_PER_THREAD)).max(MIN_PARALLEL_CHUNK);
+    reduce_in_chunks(polymers, rules, chunk_size)
 }
 
-fn str_to_char_vec(str: String) -> Vec<char> {
-    str.chars().collect()
+fn reduce_in_chunks<R: ReactionRules>(polymers: &[u8], rules: &R, chunk_size: usize) -> Vec<u8> {
+    if !rules.is_confluent() || polymers.len() <= chunk_size {
+        return reduce(polymers, rules);
+    }
+    polymers.par_chunks(chunk_size)
+        .map(|chunk| reduce(chunk, rules))
+        .reduce(Vec::new, |left, right| merge_reduced(left, &right, rules))
 }
 
-fn unique_chars(all: &Vec<char>) -> HashSet<char> {
+/// Both parts are reduced already, so only units meeting at the boundary can react.
+fn merge_reduced<R: ReactionRules>(mut left: Vec<u8>, right: &[u8], rules: &R) -> Vec<u8> {
+    let mut reacted = 0;
+    while reacted < right.len() && left.last().is_some_and(|&last| rules.reacts(last, right[reacted])) {
+        left.pop();
+        reacted += 1;
+    }
+    left.extend_from_slice(&right[reacted..]);
+    left
+}
+
+/// Two units reacting during the reduction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reaction {
+    /// Number of reactions before this one
+    pub step: usize,
+    /// Positions of the units in the original polymer
+    pub left_pos: usize,
+    pub right_pos: usize,
+    pub left: u8,
+    pub right: u8,
+}
+
+pub struct ReactionTrace {
+    pub original: Vec<u8>,
+    pub reduced: Vec<u8>,
+    pub reactions: Vec<Reaction>,
+}
+
+impl ReactionTrace {
+    pub fn reduced_str(&self) -> String {
+        String::from_utf8_lossy(&self.reduced).into_owned()
+    }
+
+    /// The original polymer followed by a line per reaction, with the units
+    /// removed so far replaced by `.`, and the reduced polymer at the end.
+    pub fn replay(&self) -> String {
+        let step_width = self.reactions.len().saturating_sub(1).to_string().len();
+        let mut state: Vec<u8> = self.original.clone();
+        let mut replay = format!("{:w$}  {}\n", "", String::from_utf8_lossy(&state), w = step_width);
+        for r in &self.reactions {
+            state[r.left_pos] = b'.';
+            state[r.right_pos] = b'.';
+            replay.push_str(&format!(
+                "{:w$}: {}  {}{} at {},{}\n",
+                r.step,
+                String::from_utf8_lossy(&state),
+                r.left as char,
+                r.right as char,
+                r.left_pos,
+                r.right_pos,
+                w = step_width
+            ));
+        }
+        replay.push_str(&format!("=> {}\n", self.reduced_str()));
+        replay
+    }
+}
+
+/// Same as `reduce`, also recording every reaction.
+pub fn reduce_traced<R: ReactionRules>(polymers: &[u8], rules: &R) -> ReactionTrace {
+    let mut left: Vec<(usize, u8)> = Vec::with_capacity(polymers.len());
+    let mut reactions = Vec::new();
+    for (pos, &unit) in polymers.iter().enumerate() {
+        match left.last() {
+            Some(&(last_pos, last)) if rules.reacts(last, unit) => {
+                left.pop();
+                reactions.push(Reaction { step: reactions.len(), left_pos: last_pos, right_pos: pos, left: last, right: unit });
+            }
+            _ => left.push((pos, unit)),
+        }
+    }
+    ReactionTrace {
+        original: polymers.to_vec(),
+        reduced: left.into_iter().map(|(_, unit)| unit).collect(),
+        reactions,
+    }
+}
+
+fn unique_unit_types<R: ReactionRules>(all: &[u8], rules: &R) -> HashSet<u8> {
     all.iter()
-        .map(|c| c.to_ascii_lowercase())
+        .map(|&c| rules.unit_type(c))
         .collect()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::mem;
+
+    use super::*;
+    use crate::common::SeededRandom;
+
+    const TEST_POLYMERS: &str = "dabAcCaCBAcCcaDA";
+
+    /// Rescans the whole polymer until no reaction happens, quadratic in the worst case.
+    fn react_multi_pass(polymers: Vec<char>) -> usize {
+        let mut before: Vec<char> = polymers;
+        let mut after: Vec<char> = Vec::with_capacity(before.len());
+        let mut reacted = true;
+        while reacted {
+            if before.is_empty() {
+                return 0;
+            }
+            after.clear();
+            reacted = false;
+            let mut bi = 0;
+            while bi < before.len() - 1 {
+                if !OppositePolarity.reacts(before[bi] as u8, before[bi + 1] as u8) {
+                    after.push(before[bi]);
+                    bi += 1;
+                } else {
+                    bi += 2;
+                    reacted = true;
+                }
+            }
+            if bi == before.len() - 1 {
+                after.push(before[bi]);
+            }
+            after = mem::replace(&mut before, after);
+        }
+        before.len()
+    }
+
+    fn get_pseudo_random_polymers(length: usize) -> Vec<u8> {
+        let mut random = SeededRandom::new(42);
+        (0..length).map(|_| b"aAbBcC"[random.below(6)]).collect()
+    }
+
+    #[test]
+    fn test_react() {
+        assert_eq!(10, react(TEST_POLYMERS.as_bytes(), &OppositePolarity));
+        assert_eq!(b"dabCBAcaDA".to_vec(), reduce(TEST_POLYMERS.as_bytes(), &OppositePolarity));
+        assert_eq!(0, react(b"aA", &OppositePolarity));
+        assert_eq!(0, react(b"", &OppositePolarity));
+        assert_eq!(4, react(b"abAB", &OppositePolarity));
+        assert_eq!(6, react(b"aabAAB", &OppositePolarity));
+    }
+
+    #[test]
+    fn test_react_matches_multi_pass() {
+        for length in 0..200 {
+            let polymers = get_pseudo_random_polymers(length);
+            let chars: Vec<char> = polymers.iter().map(|&u| u as char).collect();
+            assert_eq!(react_multi_pass(chars), react(&polymers, &OppositePolarity));
+        }
+    }
+
+    #[test]
+    fn test_min_length_after_removal() {
+        let lengths_after = lengths_after_removing_each_unit(TEST_POLYMERS.as_bytes(), &OppositePolarity);
+        assert_eq!(vec![(b'a', 6), (b'b', 8), (b'c', 4), (b'd', 6)], lengths_after);
+        assert_eq!(4, min_length(&lengths_after));
+        assert_eq!(0, min_length(&lengths_after_removing_each_unit(b"", &OppositePolarity)));
+    }
+
+    #[test]
+    fn test_removal_commutes_with_reactions() {
+        for length in (0..300).step_by(7) {
+            let polymers = get_pseudo_random_polymers(length);
+            for (unit, length_after) in lengths_after_removing_each_unit(&polymers, &OppositePolarity) {
+                assert_eq!(react(&remove_polymers(&polymers, unit, &OppositePolarity), &OppositePolarity), length_after);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reaction_rules() {
+        let table = ReactionTable::symmetric(&[(b'a', b'A'), (b'b', b'B'), (b'c', b'C'), (b'd', b'D')]);
+        assert_eq!(reduce(TEST_POLYMERS.as_bytes(), &OppositePolarity), reduce(TEST_POLYMERS.as_bytes(), &table));
+
+        let asymmetric = ReactionTable::new(&[(b'(', b')')]);
+        assert_eq!(b"".to_vec(), reduce(b"(()())", &asymmetric));
+        assert_eq!(b")(".to_vec(), reduce(b")()(", &asymmetric));
+        assert_eq!(b"(".to_vec(), reduce(b"(()", &asymmetric));
+
+        let digits = ReactionPredicate(|left: u8, right: u8| left.is_ascii_digit() && right.is_ascii_digit() && left + right == b'0' + b'9');
+        assert_eq!(b"5".to_vec(), reduce(b"1278365", &digits));
+        assert_eq!(
+            vec![(b'1', 2), (b'2', 4), (b'3', 2), (b'5', 0), (b'6', 2), (b'7', 4), (b'8', 2)],
+            lengths_after_removing_each_unit(b"1278365", &digits)
+        );
+    }
+
+    #[test]
+    fn test_reaction_trace() {
+        let trace = reduce_traced(TEST_POLYMERS.as_bytes(), &OppositePolarity);
+        assert_eq!("dabCBAcaDA", trace.reduced_str());
+        assert_eq!(
+            vec![
+                Reaction { step: 0, left_pos: 4, right_pos: 5, left: b'c', right: b'C' },
+                Reaction { step: 1, left_pos: 3, right_pos: 6, left: b'A', right: b'a' },
+                Reaction { step: 2, left_pos: 10, right_pos: 11, left: b'c', right: b'C' },
+            ],
+            trace.reactions
+        );
+        assert_eq!(
+            "   dabAcCaCBAcCcaDA\n\
+             0: dabA..aCBAcCcaDA  cC at 4,5\n\
+             1: dab....CBAcCcaDA  Aa at 3,6\n\
+             2: dab....CBA..caDA  cC at 10,11\n\
+             => dabCBAcaDA\n",
+            trace.replay()
+        );
+
+        let polymers = get_pseudo_random_polymers(500);
+        let trace = reduce_traced(&polymers, &OppositePolarity);
+        assert_eq!(reduce(&polymers, &OppositePolarity), trace.reduced);
+        assert_eq!(polymers.len(), trace.reduced.len() + 2 * trace.reactions.len());
+    }
+
+    #[test]
+    fn test_reduce_in_chunks() {
+        let polymers = get_pseudo_random_polymers(1000);
+        let reduced = reduce(&polymers, &OppositePolarity);
+        for chunk_size in 1..20 {
+            assert_eq!(reduced, reduce_in_chunks(&polymers, &OppositePolarity, chunk_size));
+        }
+        assert_eq!(reduced, reduce_parallel(&polymers, &OppositePolarity));
+        assert_eq!(b"dabCBAcaDA".to_vec(), reduce_in_chunks(TEST_POLYMERS.as_bytes(), &OppositePolarity, 3));
+        assert!(reduce_in_chunks(b"", &OppositePolarity, 3).is_empty());
+
+        let not_confluent = ReactionTable::new(&[(b'a', b'b'), (b'b', b'c')]);
+        assert_eq!(b"c".to_vec(), reduce_in_chunks(b"abc", &not_confluent, 1));
+    }
+
+}
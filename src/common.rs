@@ -3,9 +3,12 @@ use std::io::BufRead;
 use std::fs::File;
 use std::fs::read_to_string;
 
+pub fn open_file_buffered(file_name: &str) -> BufReader<File> {
+    BufReader::new(File::open(file_name).unwrap())
+}
+
 pub fn read_lines_from_file(file_name: &str) -> Vec<String> {
-    let file = File::open(file_name).unwrap();
-    let file = BufReader::new(&file);
+    let file = open_file_buffered(file_name);
     let mut result = Vec::new();
     for line in file.lines() {
         result.push(line.unwrap());
@@ -15,4 +18,23 @@ pub fn read_lines_from_file(file_name: &str) -> Vec<String> {
 
 pub fn read_file(file_name: &str) -> String {
     read_to_string(file_name).expect("Error during reading a file")
-}
\ No newline at end of file
+}
+
+/// Linear congruential generator for tests, the same seed always gives the same numbers.
+#[cfg(test)]
+pub struct SeededRandom {
+    seed: u64,
+}
+
+#[cfg(test)]
+impl SeededRandom {
+    pub fn new(seed: u64) -> SeededRandom {
+        SeededRandom { seed }
+    }
+
+    /// Next number in `0..modulo`.
+    pub fn below(&mut self, modulo: usize) -> usize {
+        self.seed = self.seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((self.seed >> 33) % modulo as u64) as usize
+    }
+}